@@ -0,0 +1,6 @@
+-- Add migration script here
+create table session (
+    session_token                   bytea primary key,
+    account_username                text not null references account on delete cascade,
+    session_expire                  timestamptz not null
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+create table command (
+    command_id                      bigserial primary key,
+    device_pubkey                   bytea not null references device on delete cascade,
+    command_properties              json not null,
+    command_created                 timestamptz not null default now()
+);
+
+create index command_device_pubkey on command (device_pubkey, command_id);
@@ -0,0 +1,9 @@
+-- Add migration script here
+alter table command
+    add column command_status       text not null default 'queued'
+        check (command_status in ('queued', 'delivered', 'acked', 'failed', 'expired')),
+    add column command_message      text,
+    add column command_updated      timestamptz not null default now();
+
+drop index command_device_pubkey;
+create index command_device_pubkey on command (device_pubkey, command_status, command_id);
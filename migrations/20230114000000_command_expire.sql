@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table command
+    add column command_expire       timestamptz;
@@ -0,0 +1,9 @@
+-- Add migration script here
+create table property_history (
+    device_pubkey                   bytea not null references device on delete cascade,
+    property_name                   text not null,
+    property_value                  json not null,
+    property_time                   timestamptz not null default now()
+);
+
+create index property_history_time on property_history (device_pubkey, property_name, property_time);
@@ -0,0 +1,47 @@
+-- Add migration script here
+create table retention_policy (
+    policy_id                       bigserial primary key,
+    account_username                text not null references account on delete cascade,
+    device_pubkey                   bytea references device on delete cascade,
+    property_name                   text,
+    policy_raw_secs                 bigint not null check (policy_raw_secs > 0),
+    policy_rollup_secs              bigint not null check (policy_rollup_secs > 0)
+);
+
+create unique index retention_policy_scope on retention_policy (
+    account_username,
+    coalesce(device_pubkey, ''::bytea),
+    coalesce(property_name, '')
+);
+
+create table property_rollup (
+    device_pubkey                   bytea not null references device on delete cascade,
+    property_name                   text not null,
+    rollup_time                     timestamptz not null,
+    rollup_min                      float8 not null,
+    rollup_max                      float8 not null,
+    rollup_avg                      float8 not null,
+    rollup_count                    bigint not null,
+    rollup_last                     float8 not null,
+    unique(device_pubkey, property_name, rollup_time)
+);
+
+-- Each linked account's most specific policy applies; across accounts the longest wins.
+create view effective_retention as
+select device_pubkey, property_name,
+    max(policy_raw_secs) as raw_secs,
+    max(policy_rollup_secs) as rollup_secs
+from (
+    select distinct on (link_account_device.account_username, property.device_pubkey, property.property_name)
+        property.device_pubkey, property.property_name, policy_raw_secs, policy_rollup_secs
+    from property
+    join link_account_device
+    on link_account_device.device_pubkey = property.device_pubkey
+    join retention_policy
+    on retention_policy.account_username = link_account_device.account_username
+        and (retention_policy.device_pubkey is null or retention_policy.device_pubkey = property.device_pubkey)
+        and (retention_policy.property_name is null or retention_policy.property_name = property.property_name)
+    order by link_account_device.account_username, property.device_pubkey, property.property_name,
+        retention_policy.property_name is null, retention_policy.device_pubkey is null
+) as per_account
+group by device_pubkey, property_name;
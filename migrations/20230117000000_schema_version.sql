@@ -0,0 +1,22 @@
+-- Add migration script here
+alter table device
+    add column device_archive_dropped boolean not null default false;
+
+create table schema_version (
+    device_pubkey                   bytea not null references device on delete cascade,
+    schema_version                  integer not null,
+    schema_value                    json not null,
+    schema_created                  timestamptz not null default now(),
+    primary key(device_pubkey, schema_version)
+);
+
+insert into schema_version (device_pubkey, schema_version, schema_value)
+select device_pubkey, 1, device_schema from device;
+
+create table property_archive (
+    device_pubkey                   bytea not null references device on delete cascade,
+    property_name                   text not null,
+    property_value                  json not null,
+    schema_version                  integer not null,
+    archive_time                    timestamptz not null default now()
+);
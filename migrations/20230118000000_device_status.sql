@@ -0,0 +1,9 @@
+-- Add migration script here
+alter table device
+    add column device_status        text not null default 'pending'
+        check (device_status in ('pending', 'accepted', 'rejected'));
+
+update device set device_status = 'accepted' where device_accepted;
+
+alter table device
+    drop column device_accepted;
@@ -0,0 +1,6 @@
+-- Add migration script here
+create table claim_code (
+    claim_code                      text primary key,
+    account_username                text not null references account on delete cascade,
+    claim_expire                    timestamptz not null
+);
@@ -0,0 +1,7 @@
+-- Add migration script here
+-- Devices linked before ownership was tracked keep every existing link as owner.
+update link_account_device
+set link_owner = true
+where device_pubkey not in (
+    select device_pubkey from link_account_device where link_owner
+);
@@ -0,0 +1,22 @@
+-- Add migration script here
+create table property_grant (
+    account_username                text not null references account on delete cascade,
+    device_pubkey                   bytea not null references device on delete cascade,
+    property_name                   text not null,
+    grant_read                      boolean not null default false,
+    grant_write                     boolean not null default false,
+    primary key(account_username, device_pubkey, property_name)
+);
+
+create function property_readable(username text, device bytea, property text)
+returns boolean language sql stable as $$
+    select exists (
+        select 1 from link_account_device
+        where account_username = username and device_pubkey = device
+            and (link_owner or link_all_property)
+    ) or exists (
+        select 1 from property_grant
+        where account_username = username and device_pubkey = device
+            and property_name = property and grant_read
+    )
+$$;
@@ -0,0 +1,5 @@
+-- Add migration script here
+alter table device
+    drop constraint device_device_status_check,
+    add constraint device_device_status_check
+        check (device_status in ('pending', 'accepted', 'rejected', 'decommissioned'));
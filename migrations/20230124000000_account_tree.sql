@@ -0,0 +1,59 @@
+-- Add migration script here
+alter table link_account_account
+    add column link_inherit_device  boolean not null default false;
+
+create function link_account_account_acyclic() returns trigger
+language plpgsql as $$
+begin
+    if new.account_username = new.derive_account_username or exists (
+        with recursive descendant(account_username) as (
+            select new.derive_account_username
+            union
+            select link_account_account.derive_account_username from link_account_account
+            join descendant
+            on link_account_account.account_username = descendant.account_username
+        )
+        select 1 from descendant where account_username = new.account_username
+    ) then
+        raise exception 'linking % under % would create a cycle',
+            new.derive_account_username, new.account_username;
+    end if;
+    return new;
+end
+$$;
+
+create trigger link_account_account_acyclic
+before insert or update on link_account_account
+for each row execute function link_account_account_acyclic();
+
+-- Device links of an account plus those inherited from descendants whose
+-- account links have `link_inherit_device` all the way up.
+create view account_device as
+with recursive inherited(account_username, device_pubkey, link_owner, link_all_property) as (
+    select account_username, device_pubkey, link_owner, link_all_property
+    from link_account_device
+    union
+    select link_account_account.account_username, inherited.device_pubkey,
+        inherited.link_owner, inherited.link_all_property
+    from inherited join link_account_account
+    on link_account_account.derive_account_username = inherited.account_username
+    where link_inherit_device
+)
+select account_username, device_pubkey,
+    bool_or(link_owner) as link_owner,
+    bool_or(link_all_property) as link_all_property
+from inherited
+group by account_username, device_pubkey;
+
+create or replace function property_readable(username text, device bytea, property text)
+returns boolean language sql stable as $$
+    select exists (
+        select 1 from account_device
+        where account_username = username and device_pubkey = device
+            and (link_owner or link_all_property)
+    ) or exists (
+        select 1 from property_grant
+        where account_username = username and device_pubkey = device
+            and property_name = property and grant_read
+    )
+$$;
@@ -0,0 +1,6 @@
+-- Add migration script here
+alter table account
+    add column account_role         text not null default 'user'
+        check (account_role in ('superadmin', 'installer', 'user', 'viewer'));
+
+update account set account_role = 'superadmin' where account_username = 'admin';
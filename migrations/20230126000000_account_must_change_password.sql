@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table account
+    add column account_must_change_password boolean not null default false;
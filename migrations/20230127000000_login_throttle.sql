@@ -0,0 +1,7 @@
+-- Add migration script here
+create table login_throttle (
+    throttle_key                    text primary key,
+    throttle_failures               integer not null default 0,
+    throttle_last_failure           timestamptz not null default now(),
+    throttle_until                  timestamptz not null default now()
+);
@@ -0,0 +1,11 @@
+-- Add migration script here
+alter table account
+    add column account_totp_secret  bytea,
+    add column account_totp_enabled boolean not null default false,
+    add column account_totp_last_step bigint;
+
+create table recovery_code (
+    account_username                text not null references account on delete cascade,
+    recovery_code_hash              bytea not null,
+    primary key(account_username, recovery_code_hash)
+);
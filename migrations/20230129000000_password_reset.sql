@@ -0,0 +1,9 @@
+-- Add migration script here
+alter table account
+    add column account_email        text;
+
+create table password_reset (
+    password_reset_hash             bytea primary key,
+    account_username                text not null references account on delete cascade,
+    password_reset_expire           timestamptz not null
+);
@@ -0,0 +1,25 @@
+-- Add migration script here
+-- Only owners' policies apply, and they cover every property with stored
+-- history or rollups, including archived ones no longer in `property`.
+create or replace view effective_retention as
+select device_pubkey, property_name,
+    max(policy_raw_secs) as raw_secs,
+    max(policy_rollup_secs) as rollup_secs
+from (
+    select distinct on (account_device.account_username, stored.device_pubkey, stored.property_name)
+        stored.device_pubkey, stored.property_name, policy_raw_secs, policy_rollup_secs
+    from (
+        select distinct device_pubkey, property_name from property_history
+        union
+        select distinct device_pubkey, property_name from property_rollup
+    ) as stored
+    join account_device
+    on account_device.device_pubkey = stored.device_pubkey and account_device.link_owner
+    join retention_policy
+    on retention_policy.account_username = account_device.account_username
+        and (retention_policy.device_pubkey is null or retention_policy.device_pubkey = stored.device_pubkey)
+        and (retention_policy.property_name is null or retention_policy.property_name = stored.property_name)
+    order by account_device.account_username, stored.device_pubkey, stored.property_name,
+        retention_policy.property_name is null, retention_policy.device_pubkey is null
+) as per_account
+group by device_pubkey, property_name;
@@ -0,0 +1,67 @@
+-- Add migration script here
+-- Device links of `username` plus those inherited from descendants whose
+-- account links have `link_inherit_device` all the way down. Seeded by the
+-- account asked about instead of walking the whole link graph.
+create function account_devices(username text)
+returns table(device_pubkey bytea, link_owner boolean, link_all_property boolean)
+language sql stable as $$
+    with recursive member(account_username) as (
+        select username
+        union
+        select link_account_account.derive_account_username
+        from member join link_account_account
+        on link_account_account.account_username = member.account_username
+        where link_inherit_device
+    )
+    select link_account_device.device_pubkey,
+        bool_or(link_account_device.link_owner),
+        bool_or(link_account_device.link_all_property)
+    from member join link_account_device
+    on link_account_device.account_username = member.account_username
+    group by link_account_device.device_pubkey
+$$;
+
+create or replace function property_readable(username text, device bytea, property text)
+returns boolean language sql stable as $$
+    select exists (
+        select 1 from account_devices(username) as account_device
+        where account_device.device_pubkey = device
+            and (account_device.link_owner or account_device.link_all_property)
+    ) or exists (
+        select 1 from property_grant
+        where account_username = username and device_pubkey = device
+            and property_name = property and grant_read
+    )
+$$;
+
+-- Only owners' policies apply, and they cover every property with stored
+-- history or rollups, including archived ones no longer in `property`.
+create or replace view effective_retention as
+select device_pubkey, property_name,
+    max(policy_raw_secs) as raw_secs,
+    max(policy_rollup_secs) as rollup_secs
+from (
+    select distinct on (policy_account.account_username, stored.device_pubkey, stored.property_name)
+        stored.device_pubkey, stored.property_name, policy_raw_secs, policy_rollup_secs
+    from (
+        select distinct device_pubkey, property_name from property_history
+        union
+        select distinct device_pubkey, property_name from property_rollup
+    ) as stored
+    join (
+        select distinct retention_policy.account_username, account_device.device_pubkey
+        from retention_policy
+        cross join lateral account_devices(retention_policy.account_username) as account_device
+        where account_device.link_owner
+    ) as policy_account
+    on policy_account.device_pubkey = stored.device_pubkey
+    join retention_policy
+    on retention_policy.account_username = policy_account.account_username
+        and (retention_policy.device_pubkey is null or retention_policy.device_pubkey = stored.device_pubkey)
+        and (retention_policy.property_name is null or retention_policy.property_name = stored.property_name)
+    order by policy_account.account_username, stored.device_pubkey, stored.property_name,
+        retention_policy.property_name is null, retention_policy.device_pubkey is null
+) as per_account
+group by device_pubkey, property_name;
+
+drop view account_device;
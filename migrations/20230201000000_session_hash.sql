@@ -0,0 +1,7 @@
+-- Add migration script here
+-- Sessions are looked up by the SHA-256 of their token from now on, so the
+-- plaintext tokens stored so far can never match again.
+delete from session;
+
+alter table session
+    rename column session_token to session_hash;
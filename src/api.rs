@@ -1,11 +1,16 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, net::SocketAddr};
 
+use argon2::password_hash::rand_core::{OsRng, RngCore};
 use base58::{FromBase58, ToBase58};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tide::{Request, Response};
+use tide::{Middleware, Next, Request, Response};
 
-use crate::database::{self, *};
+use crate::{
+    database::{self, *},
+    notify, password, schema, totp,
+};
 
 #[derive(Serialize)]
 pub struct ApiResult<T: Serialize> {
@@ -31,96 +36,311 @@ impl<T: Serialize> ApiResult<T> {
     }
 }
 
-impl<T: Serialize> Into<tide::Result> for ApiResult<T> {
-    fn into(self) -> tide::Result {
+impl<T: Serialize> From<ApiResult<T>> for tide::Result {
+    fn from(result: ApiResult<T>) -> tide::Result {
         Ok(Response::builder(200)
-            .body(serde_json::to_value(&self)?)
+            .body(serde_json::to_value(&result)?)
             .build())
     }
 }
 
-pub async fn create_account(mut req: Request<()>) -> tide::Result {
+struct SessionToken(Vec<u8>);
+
+/// Routes still open to an account that must change its password.
+const PASSWORD_CHANGE_ROUTES: &[&str] = &[
+    "/account/new_password",
+    "/session/delete",
+    "/session/delete_all",
+];
+
+const PASSWORD_CHANGE_REQUIRED: &str = "Password change required";
+
+/// Whether `account` must change its password before it may use the route
+/// `req` is for.
+fn must_change_password(account: &Account, req: &Request<()>) -> bool {
+    account.account_must_change_password && !PASSWORD_CHANGE_ROUTES.contains(&req.url().path())
+}
+
+/// Resolves the `Authorization: Bearer <token>` header into the session's
+/// [`Account`], which handlers mounted behind it read with [`account`].
+pub struct Authenticate;
+
+#[tide::utils::async_trait]
+impl Middleware<()> for Authenticate {
+    async fn handle(&self, mut req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        if let Some((account, token)) = session(&req).await? {
+            if must_change_password(&account, &req) {
+                return ApiResult::failure(PASSWORD_CHANGE_REQUIRED, ()).into();
+            }
+            req.set_ext(account);
+            req.set_ext(SessionToken(token));
+            Ok(next.run(req).await)
+        } else {
+            ApiResult::failure("Invalid session", ()).into()
+        }
+    }
+}
+
+async fn session(req: &Request<()>) -> anyhow::Result<Option<(Account, Vec<u8>)>> {
+    let token = req
+        .header("Authorization")
+        .and_then(|h| h.as_str().strip_prefix("Bearer "))
+        .and_then(|token| token.trim().from_base58().ok());
+    if let Some(token) = token {
+        Ok(db_get_session_account(&token)
+            .await?
+            .map(|account| (account, token)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Rejects sessions whose account role is below the route's.
+pub struct Require(pub Role);
+
+#[tide::utils::async_trait]
+impl Middleware<()> for Require {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        if account(&req).role() >= self.0 {
+            Ok(next.run(req).await)
+        } else {
+            ApiResult::failure("Permission denied", ()).into()
+        }
+    }
+}
+
+/// Whether `caller` may manage `username`: superadmins manage everyone,
+/// other accounts the accounts below them.
+async fn manages(caller: &Account, username: &str) -> anyhow::Result<bool> {
+    Ok(caller.role() == Role::Superadmin
+        || db_is_ancestor(&caller.account_username, username).await?)
+}
+
+fn account(req: &Request<()>) -> Account {
+    req.ext::<Account>()
+        .cloned()
+        .expect("handler must be mounted behind Authenticate")
+}
+
+const THROTTLED: &str = "Too many failed attempts, try again later";
+
+/// Failed attempts allowed before backoff kicks in.
+const FREE_ATTEMPTS: i32 = 3;
+/// Failed attempts after which a key is locked out for [`LOCKOUT`].
+const LOCKOUT_ATTEMPTS: i32 = 10;
+const LOCKOUT: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// How long a key is blocked after its `failures`th failed attempt: not at
+/// all at first, then doubling from one second, then [`LOCKOUT`].
+fn backoff(failures: i32) -> std::time::Duration {
+    if failures >= LOCKOUT_ATTEMPTS {
+        LOCKOUT
+    } else if failures <= FREE_ATTEMPTS {
+        std::time::Duration::ZERO
+    } else {
+        std::time::Duration::from_secs(1 << (failures - FREE_ATTEMPTS - 1))
+    }
+}
+
+fn client_ip(req: &Request<()>) -> String {
+    req.peer_addr()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+        .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
+}
+
+pub async fn login(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
-    struct AccountInfo {
-        name: String,
+    struct Login {
         username: String,
         password: String,
-        owner: Option<String>,
+        code: Option<String>,
     }
-
-    if let Ok(AccountInfo {
-        name,
+    #[derive(Serialize)]
+    struct Output {
+        token: String,
+        expire: DateTime<Utc>,
+        must_change_password: bool,
+    }
+    let ip = client_ip(&req);
+    if let Ok(Login {
         username,
         password,
-        owner,
+        code,
     }) = req.body_json().await
     {
-        if let Some(_) = db_get_account(&username).await? {
-            ApiResult::failure("Account existed", ()).into()
-        } else {
-            if let Some(owner) = owner {
-                if let Some(owner) = db_get_account(&owner).await? {
-                    db_create_account(&username, &password, &owner.account_username, &name).await?;
-                    ApiResult::failure("Not implement yet!", ()).into()
-                } else {
-                    ApiResult::failure("Account not found", ()).into()
+        let keys = [format!("account:{}", username), format!("ip:{}", ip)];
+        if let Some(until) = db_throttle_until(&keys).await? {
+            return ApiResult::failure(THROTTLED, until).into();
+        }
+        match db_get_account(&username).await? {
+            Some(account) if account.valid_password(&password) => {
+                if account.account_disabled {
+                    return ApiResult::failure("Account disabled", ()).into();
                 }
-            } else {
-                if let Ok(_) = db_create_account(&username, &password, "admin", &name).await {
-                    ApiResult::success("Succes", ()).into()
-                } else {
-                    ApiResult::failure("Failed to create account", ()).into()
+                if let Some(secret) = totp_secret(&username).await? {
+                    let Some(code) = code else {
+                        return ApiResult::failure("Two-factor code required", ()).into();
+                    };
+                    if !second_factor(&username, &secret, &code).await? {
+                        for key in &keys {
+                            db_record_login_failure(key, backoff).await?;
+                        }
+                        return ApiResult::failure("Invalid two-factor code", ()).into();
+                    }
+                }
+                db_clear_login_failures(&keys[0]).await?;
+                if password::needs_rehash(&account.account_password) {
+                    db_rehash_password(&username, &password).await?;
+                }
+                let session = db_create_session(&username).await?;
+                let output = Output {
+                    token: session.session_token.to_base58(),
+                    expire: session.session_expire,
+                    must_change_password: account.account_must_change_password,
+                };
+                ApiResult::success("Success", output).into()
+            }
+            account => {
+                if account.is_none() {
+                    password::verify_dummy(&password);
+                }
+                for key in &keys {
+                    db_record_login_failure(key, backoff).await?;
                 }
+                ApiResult::failure("Invalid username or password", ()).into()
             }
         }
     } else {
-        ApiResult::failure("Invalid Password", ()).into()
+        ApiResult::failure("Invalid input", ()).into()
     }
 }
 
-pub async fn get_account_name(mut req: Request<()>) -> tide::Result {
-    #[derive(Deserialize, Debug)]
-    struct Login {
+/// The account's TOTP secret, if two-factor authentication is enabled.
+async fn totp_secret(username: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(db_get_totp(username)
+        .await?
+        .filter(|totp| totp.account_totp_enabled)
+        .and_then(|totp| totp.account_totp_secret))
+}
+
+/// Checks a second factor, either a TOTP code or an unused recovery code.
+async fn second_factor(username: &str, secret: &[u8], code: &str) -> anyhow::Result<bool> {
+    if let Some(step) = totp::verify(secret, code) {
+        db_use_totp_step(username, step).await
+    } else {
+        db_use_recovery_code(username, code).await
+    }
+}
+
+fn recovery_codes() -> Vec<String> {
+    (0..10)
+        .map(|_| {
+            let mut code = [0u8; 8];
+            OsRng.fill_bytes(&mut code);
+            code.to_base58()
+        })
+        .collect()
+}
+
+pub async fn logout(req: Request<()>) -> tide::Result {
+    if let Some(SessionToken(token)) = req.ext() {
+        db_delete_session(token).await?;
+    }
+    ApiResult::success("Success", ()).into()
+}
+
+pub async fn logout_all(req: Request<()>) -> tide::Result {
+    db_delete_all_session(&account(&req).account_username).await?;
+    ApiResult::success("Success", ()).into()
+}
+
+/// Creates an account, under `owner` for signed-in callers or as a
+/// self-registration otherwise. Probing taken usernames counts against the
+/// caller's IP like a failed login.
+pub async fn create_account(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct AccountInfo {
+        name: String,
         username: String,
         password: String,
+        owner: Option<String>,
     }
-    if let Ok(Login { username, password }) = dbg!(req.body_json().await) {
-        if let Some(account) = dbg!(db_get_account(&username).await?) {
-            if account.valid_password(&password) {
-                ApiResult::success("Success", account.account_name).into()
-            } else {
-                ApiResult::failure("Invalid Password", ()).into()
+
+    let ip_key = format!("ip:{}", client_ip(&req));
+    if let Ok(AccountInfo {
+        name,
+        username,
+        password,
+        owner,
+    }) = req.body_json().await
+    {
+        if let Some(until) = db_throttle_until(std::slice::from_ref(&ip_key)).await? {
+            return ApiResult::failure(THROTTLED, until).into();
+        }
+        let owner = if let Some(owner) = owner {
+            let caller = match session(&req).await? {
+                Some((caller, _)) => caller,
+                None => return ApiResult::failure("Invalid session", ()).into(),
+            };
+            if must_change_password(&caller, &req) {
+                return ApiResult::failure(PASSWORD_CHANGE_REQUIRED, ()).into();
+            } else if db_get_account(&owner).await?.is_none() {
+                return ApiResult::failure("Account not found", ()).into();
+            } else if caller.role() < Role::User
+                || (caller.account_username != owner && !manages(&caller, &owner).await?)
+            {
+                return ApiResult::failure("Permission denied", ()).into();
             }
+            owner
         } else {
-            ApiResult::failure("Account not found", ()).into()
+            ADMIN.clone()
+        };
+        if db_get_account(&username).await?.is_some() {
+            db_record_login_failure(&ip_key, backoff).await?;
+            ApiResult::failure("Account existed", ()).into()
+        } else if let Err(e) = password::check(&username, &password) {
+            ApiResult::failure(&e, ()).into()
+        } else if db_create_account(&username, &password, &owner, &name)
+            .await
+            .is_ok()
+        {
+            ApiResult::success("Success", ()).into()
+        } else {
+            ApiResult::failure("Failed to create account", ()).into()
         }
     } else {
-        ApiResult::failure("Invalid input", ()).into()
+        ApiResult::failure("Invalid Password", ()).into()
     }
 }
 
+pub async fn get_account_name(req: Request<()>) -> tide::Result {
+    ApiResult::success("Success", account(&req).account_name).into()
+}
+
 pub async fn chpasswd(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
-        username: String,
         password: String,
         new_password: String,
     }
+    let account = account(&req);
     if let Ok(Input {
-        username,
         password,
         new_password,
     }) = req.body_json().await
     {
-        if let Some(account) = db_get_account(&username).await? {
-            if account.valid_password(&password) {
-                db_change_password(&username, &new_password).await?;
-                ApiResult::success("Success", ()).into()
-            } else {
-                ApiResult::failure("Invalid password", ()).into()
-            }
+        let key = format!("account:{}", account.account_username);
+        if let Some(until) = db_throttle_until(std::slice::from_ref(&key)).await? {
+            ApiResult::failure(THROTTLED, until).into()
+        } else if !account.valid_password(&password) {
+            db_record_login_failure(&key, backoff).await?;
+            ApiResult::failure("Invalid password", ()).into()
+        } else if let Err(e) = password::check(&account.account_username, &new_password) {
+            ApiResult::failure(&e, ()).into()
         } else {
-            ApiResult::failure("Account not found", ()).into()
+            db_clear_login_failures(&key).await?;
+            db_change_password(&account.account_username, &new_password).await?;
+            ApiResult::success("Success", ()).into()
         }
     } else {
         ApiResult::failure("Invalid input", ()).into()
@@ -128,19 +348,31 @@ pub async fn chpasswd(mut req: Request<()>) -> tide::Result {
 }
 
 pub async fn list_account(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize, Default)]
+    struct Input {
+        #[serde(default)]
+        recursive: bool,
+    }
+    let username = account(&req).account_username;
+    let Input { recursive } = req.body_json().await.unwrap_or_default();
+    let list = if recursive {
+        db_get_descendant_accounts(&username).await?
+    } else {
+        db_get_all_account(&username).await?
+    };
+    ApiResult::success("", list).into()
+}
+pub async fn set_inherit_device(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
         username: String,
-        password: String,
+        enabled: bool,
     }
-    if let Ok(Input { username, password }) = req.body_json().await {
-        if let Some(account) = db_get_account(&username).await? {
-            if account.valid_password(&password) {
-                let list = db_get_all_account(&username).await?;
-                ApiResult::success("", list).into()
-            } else {
-                ApiResult::failure("Password incorrect", ()).into()
-            }
+
+    let owner = account(&req).account_username;
+    if let Ok(Input { username, enabled }) = req.body_json().await {
+        if db_set_inherit_device(&owner, &username, enabled).await? {
+            ApiResult::success("Success", ()).into()
         } else {
             ApiResult::failure("Account not found", ()).into()
         }
@@ -148,74 +380,115 @@ pub async fn list_account(mut req: Request<()>) -> tide::Result {
         ApiResult::failure("Invalid Input", ()).into()
     }
 }
-pub async fn list_device(mut req: Request<()>) -> tide::Result {
+pub async fn link_account(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
+        owner: String,
         username: String,
-        password: String,
     }
-    if let Ok(Input { username, password }) = req.body_json().await {
-        if let Some(account) = db_get_account(&username).await? {
-            if account.valid_password(&password) {
-                #[derive(Serialize)]
-                struct Device {
-                    pub device_pubkey: String,
-                    pub device_accepted: bool,
-                    pub device_title: String,
-                    pub device_local_ip: String,
-                    pub device_schema: Value,
-                }
-                impl From<database::Device> for Device {
-                    fn from(device: database::Device) -> Device {
-                        Device {
-                            device_pubkey: device.device_pubkey.to_base58(),
-                            device_accepted: device.device_accepted,
-                            device_title: device.device_title,
-                            device_local_ip: device.device_local_ip,
-                            device_schema: device.device_schema,
-                        }
-                    }
-                }
-                let devices = db_get_device_by_username(&username).await?;
 
-                let devices: Vec<Device> = devices.into_iter().map(|d| d.into()).collect();
-                ApiResult::success("", devices).into()
-            } else {
-                ApiResult::failure("Password incorrect", ()).into()
-            }
+    let caller = account(&req);
+    if let Ok(Input { owner, username }) = req.body_json().await {
+        if db_get_account(&owner).await?.is_none() || db_get_account(&username).await?.is_none() {
+            ApiResult::failure("Account not found", ()).into()
+        } else if (caller.account_username != owner && !manages(&caller, &owner).await?)
+            || !manages(&caller, &username).await?
+        {
+            ApiResult::failure("Permission denied", ()).into()
+        } else if owner == username || db_is_ancestor(&username, &owner).await? {
+            ApiResult::failure("Link would create a cycle", ()).into()
         } else {
+            db_link_account(&owner, &username).await?;
+            ApiResult::success("Success", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+#[derive(Serialize)]
+struct Device {
+    pub device_pubkey: String,
+    pub device_status: String,
+    pub device_title: String,
+    pub device_local_ip: String,
+    pub device_schema: Value,
+    pub link_owner: bool,
+    pub link_all_property: bool,
+}
+impl From<database::Device> for Device {
+    fn from(device: database::Device) -> Device {
+        Device {
+            device_pubkey: device.device_pubkey.to_base58(),
+            device_status: device.device_status,
+            device_title: device.device_title,
+            device_local_ip: device.device_local_ip,
+            device_schema: device.device_schema,
+            link_owner: device.link_owner,
+            link_all_property: device.link_all_property,
+        }
+    }
+}
+async fn manage_account(mut req: Request<()>, disabled: Option<bool>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        username: String,
+    }
+
+    let caller = account(&req);
+    if let Ok(Input { username }) = req.body_json().await {
+        if db_get_account(&username).await?.is_none() {
             ApiResult::failure("Account not found", ()).into()
+        } else if caller.account_username == username || !manages(&caller, &username).await? {
+            ApiResult::failure("Permission denied", ()).into()
+        } else {
+            if let Some(disabled) = disabled {
+                db_set_account_disabled(&username, disabled).await?;
+            } else {
+                db_delete_account(&username).await?;
+            }
+            ApiResult::success("Success", ()).into()
         }
     } else {
         ApiResult::failure("Invalid Input", ()).into()
     }
 }
+pub async fn disable_account(req: Request<()>) -> tide::Result {
+    manage_account(req, Some(true)).await
+}
+pub async fn enable_account(req: Request<()>) -> tide::Result {
+    manage_account(req, Some(false)).await
+}
+pub async fn delete_account(req: Request<()>) -> tide::Result {
+    manage_account(req, None).await
+}
+pub async fn list_device(req: Request<()>) -> tide::Result {
+    let devices = db_get_device_by_username(&account(&req).account_username).await?;
+
+    let devices: Vec<Device> = devices.into_iter().map(|d| d.into()).collect();
+    ApiResult::success("", devices).into()
+}
+pub async fn list_pending_device(req: Request<()>) -> tide::Result {
+    let devices = db_get_device_by_username(&account(&req).account_username).await?;
+
+    let devices: Vec<Device> = devices
+        .into_iter()
+        .filter(|d| d.device_status == "pending")
+        .map(|d| d.into())
+        .collect();
+    ApiResult::success("", devices).into()
+}
 pub async fn get_schema(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
-        username: String,
-        password: String,
         device: String,
     }
-    if let Ok(Input {
-        username,
-        password,
-        device,
-    }) = req.body_json().await
-    {
+    let username = account(&req).account_username;
+    if let Ok(Input { device }) = req.body_json().await {
         if let Ok(pubkey) = device.from_base58() {
-            if let Some(account) = db_get_account(&username).await? {
-                if account.valid_password(&password) {
-                    if let Some(device) = db_get_device(&username, &pubkey).await? {
-                        ApiResult::success("", device.device_schema).into()
-                    } else {
-                        ApiResult::failure("Device not found", ()).into()
-                    }
-                } else {
-                    ApiResult::failure("Password incorrect", ()).into()
-                }
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                ApiResult::success("", device.device_schema).into()
             } else {
-                ApiResult::failure("Account not found", ()).into()
+                ApiResult::failure("Device not found", ()).into()
             }
         } else {
             ApiResult::failure("Invalid Input", ()).into()
@@ -224,32 +497,64 @@ pub async fn get_schema(mut req: Request<()>) -> tide::Result {
         ApiResult::failure("Invalid Input", ()).into()
     }
 }
+/// Longest `ttl` a command may ask for, one year in seconds.
+const MAX_COMMAND_TTL: i64 = 365 * 24 * 60 * 60;
+
 pub async fn set_properties(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
-        username: String,
-        password: String,
         device: String,
         properties: BTreeMap<String, Value>,
+        ttl: Option<i64>,
+        expire: Option<DateTime<Utc>>,
     }
 
+    let username = account(&req).account_username;
     if let Ok(Input {
-        username,
-        password,
         device,
         properties,
+        ttl,
+        expire,
     }) = req.body_json().await
     {
+        let ttl = match ttl {
+            Some(ttl) if !(1..=MAX_COMMAND_TTL).contains(&ttl) => {
+                return ApiResult::failure("Invalid Input", ()).into()
+            }
+            Some(ttl) => match Utc::now().checked_add_signed(Duration::seconds(ttl)) {
+                Some(ttl) => Some(ttl),
+                None => return ApiResult::failure("Invalid Input", ()).into(),
+            },
+            None => None,
+        };
+        let expire = match (ttl, expire) {
+            (Some(ttl), Some(expire)) => Some(ttl.min(expire)),
+            (ttl, expire) => ttl.or(expire),
+        };
         if let Ok(pubkey) = device.from_base58() {
-            if let Some(account) = db_get_account(&username).await? {
-                if account.valid_password(&password) {
-                    crate::remote::set_wait(&pubkey, properties).await;
-                    ApiResult::success("", ()).into()
-                } else {
-                    ApiResult::failure("Password incorrect", ()).into()
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                // `link_all_property` only grants reading; viewers need write grants.
+                if !device.link_owner {
+                    let grants = db_get_account_property_grants(&username, &pubkey).await?;
+                    let writable = |name: &String| {
+                        grants
+                            .iter()
+                            .any(|g| &g.property_name == name && g.grant_write)
+                    };
+                    if !properties.keys().all(writable) {
+                        return ApiResult::failure("Permission denied", ()).into();
+                    }
                 }
+                if device.device_status != "accepted" {
+                    return ApiResult::failure("Device not accepted", ()).into();
+                }
+                if let Err(message) = schema::validate(&device.device_schema, &properties, true) {
+                    return ApiResult::failure(&message, ()).into();
+                }
+                let id = crate::remote::set_wait(&pubkey, properties, expire).await?;
+                ApiResult::success("", id).into()
             } else {
-                ApiResult::failure("Account not found", ()).into()
+                ApiResult::failure("Device not found", ()).into()
             }
         } else {
             ApiResult::failure("Invalid Input", ()).into()
@@ -261,32 +566,81 @@ pub async fn set_properties(mut req: Request<()>) -> tide::Result {
 pub async fn get_properties(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
-        username: String,
-        password: String,
         device: String,
         property: String,
     }
 
+    let username = account(&req).account_username;
+    if let Ok(Input { device, property }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(value) = db_get_property(&username, &pubkey, &property).await? {
+                ApiResult::success("", value).into()
+            } else {
+                ApiResult::failure("Property not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Bounds on `bucket` widths in seconds: a second up to a hundred years,
+/// which also keeps NaN and infinities out of `date_bin`.
+const MIN_BUCKET: f64 = 1.0;
+const MAX_BUCKET: f64 = 100.0 * 366.0 * 24.0 * 60.0 * 60.0;
+
+pub async fn get_property_history(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        property: String,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        limit: Option<i64>,
+        #[serde(default)]
+        order: Order,
+        bucket: Option<f64>,
+    }
+
+    let username = account(&req).account_username;
     if let Ok(Input {
-        username,
-        password,
         device,
         property,
+        from,
+        to,
+        limit,
+        order,
+        bucket,
     }) = req.body_json().await
     {
         if let Ok(pubkey) = device.from_base58() {
-            if let Some(account) = db_get_account(&username).await? {
-                if account.valid_password(&password) {
-                    if let Some(value) = db_get_property(&username, &pubkey, &property).await? {
-                        ApiResult::success("", value).into()
-                    } else {
-                        ApiResult::failure("Property not found", ()).into()
-                    }
-                } else {
-                    ApiResult::failure("Password incorrect", ()).into()
+            let limit = limit.unwrap_or(1000).clamp(1, 10000);
+            let ascending = order == Order::Asc;
+            match bucket {
+                Some(bucket) if (MIN_BUCKET..=MAX_BUCKET).contains(&bucket) => {
+                    let buckets = db_get_property_buckets(
+                        &username, &pubkey, &property, from, to, bucket, limit, ascending,
+                    )
+                    .await?;
+                    ApiResult::success("", buckets).into()
+                }
+                Some(_) => ApiResult::failure("Invalid bucket width", ()).into(),
+                None => {
+                    let history = db_get_property_history(
+                        &username, &pubkey, &property, from, to, limit, ascending,
+                    )
+                    .await?;
+                    ApiResult::success("", history).into()
                 }
-            } else {
-                ApiResult::failure("Account not found", ()).into()
             }
         } else {
             ApiResult::failure("Invalid Input", ()).into()
@@ -295,33 +649,61 @@ pub async fn get_properties(mut req: Request<()>) -> tide::Result {
         ApiResult::failure("Invalid Input", ()).into()
     }
 }
-pub async fn get_local_ip(mut req: Request<()>) -> tide::Result {
+pub async fn get_property_rollup(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
-        username: String,
-        password: String,
         device: String,
+        property: String,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        limit: Option<i64>,
+        #[serde(default)]
+        order: Order,
     }
 
+    let username = account(&req).account_username;
     if let Ok(Input {
-        username,
-        password,
         device,
+        property,
+        from,
+        to,
+        limit,
+        order,
     }) = req.body_json().await
     {
         if let Ok(pubkey) = device.from_base58() {
-            if let Some(account) = db_get_account(&username).await? {
-                if account.valid_password(&password) {
-                    if let Some(device) = db_get_device(&username, &pubkey).await? {
-                        ApiResult::success("", device.device_local_ip).into()
-                    } else {
-                        ApiResult::failure("Device not found", ()).into()
-                    }
-                } else {
-                    ApiResult::failure("Password incorrect", ()).into()
-                }
+            let limit = limit.unwrap_or(1000).clamp(1, 10000);
+            let rollups = db_get_property_rollups(
+                &username,
+                &pubkey,
+                &property,
+                from,
+                to,
+                limit,
+                order == Order::Asc,
+            )
+            .await?;
+            ApiResult::success("", rollups).into()
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn get_local_ip(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                ApiResult::success("", device.device_local_ip).into()
             } else {
-                ApiResult::failure("Account not found", ()).into()
+                ApiResult::failure("Device not found", ()).into()
             }
         } else {
             ApiResult::failure("Invalid Input", ()).into()
@@ -331,34 +713,43 @@ pub async fn get_local_ip(mut req: Request<()>) -> tide::Result {
     }
 }
 
-pub async fn accept_device(mut req: Request<()>) -> tide::Result {
+pub async fn new_claim_code(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize, Default)]
+    struct Input {
+        username: Option<String>,
+    }
+
+    let caller = account(&req);
+    let Input { username } = req.body_json().await.unwrap_or_default();
+    let username = match username {
+        Some(username) if username != caller.account_username => {
+            if caller.role() < Role::Installer || !manages(&caller, &username).await? {
+                return ApiResult::failure("Permission denied", ()).into();
+            }
+            username
+        }
+        _ => caller.account_username,
+    };
+    let claim = db_create_claim_code(&username).await?;
+    ApiResult::success("", claim).into()
+}
+async fn set_device_status(mut req: Request<()>, status: &str) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
-        username: String,
-        password: String,
         device: String,
     }
 
-    if let Ok(Input {
-        username,
-        password,
-        device,
-    }) = req.body_json().await
-    {
+    let username = account(&req).account_username;
+    if let Ok(Input { device }) = req.body_json().await {
         if let Ok(pubkey) = device.from_base58() {
-            if let Some(account) = db_get_account(&username).await? {
-                if account.valid_password(&password) {
-                    if let Some(device) = db_get_device(&username, &pubkey).await? {
-                        db_accept_device(&username, &pubkey).await?;
-                        ApiResult::success("", device.device_local_ip).into()
-                    } else {
-                        ApiResult::failure("Device not found", ()).into()
-                    }
-                } else {
-                    ApiResult::failure("Password incorrect", ()).into()
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
                 }
+                db_set_device_status(&username, &pubkey, status).await?;
+                ApiResult::success("", ()).into()
             } else {
-                ApiResult::failure("Account not found", ()).into()
+                ApiResult::failure("Device not found", ()).into()
             }
         } else {
             ApiResult::failure("Invalid Input", ()).into()
@@ -367,36 +758,74 @@ pub async fn accept_device(mut req: Request<()>) -> tide::Result {
         ApiResult::failure("Invalid Input", ()).into()
     }
 }
-pub async fn set_title(mut req: Request<()>) -> tide::Result {
+pub async fn accept_device(req: Request<()>) -> tide::Result {
+    set_device_status(req, "accepted").await
+}
+pub async fn reject_device(req: Request<()>) -> tide::Result {
+    set_device_status(req, "rejected").await
+}
+pub async fn decommission_device(req: Request<()>) -> tide::Result {
+    set_device_status(req, "decommissioned").await
+}
+pub async fn export_device(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
-        username: String,
-        password: String,
         device: String,
-        title: String,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        limit: Option<i64>,
+    }
+    #[derive(Serialize)]
+    struct Export {
+        device: Device,
+        history: Vec<PropertyExport>,
     }
 
+    let username = account(&req).account_username;
     if let Ok(Input {
-        username,
-        password,
         device,
-        title,
+        from,
+        to,
+        limit,
     }) = req.body_json().await
     {
         if let Ok(pubkey) = device.from_base58() {
-            if let Some(account) = db_get_account(&username).await? {
-                if account.valid_password(&password) {
-                    if let Some(device) = db_get_device(&username, &pubkey).await? {
-                        db_device_new_title(&username, &pubkey, &title).await?;
-                        ApiResult::success("", device.device_local_ip).into()
-                    } else {
-                        ApiResult::failure("Device not found", ()).into()
-                    }
-                } else {
-                    ApiResult::failure("Password incorrect", ()).into()
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                let limit = limit.unwrap_or(1000).clamp(1, 10000);
+                let history = db_export_device_history(&username, &pubkey, from, to, limit).await?;
+                let export = Export {
+                    device: device.into(),
+                    history,
+                };
+                ApiResult::success("", export).into()
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn delete_device(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
                 }
+                db_delete_device(&username, &pubkey).await?;
+                crate::remote::forget_device(&pubkey).await;
+                ApiResult::success("", ()).into()
             } else {
-                ApiResult::failure("Account not found", ()).into()
+                ApiResult::failure("Device not found", ()).into()
             }
         } else {
             ApiResult::failure("Invalid Input", ()).into()
@@ -405,3 +834,818 @@ pub async fn set_title(mut req: Request<()>) -> tide::Result {
         ApiResult::failure("Invalid Input", ()).into()
     }
 }
+pub async fn set_title(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        title: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device, title }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                db_device_new_title(&username, &pubkey, &title).await?;
+                ApiResult::success("", device.device_local_ip).into()
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn get_command(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        id: i64,
+    }
+    #[derive(Serialize)]
+    struct Command {
+        id: i64,
+        device: String,
+        properties: Value,
+        status: String,
+        message: Option<String>,
+        created: DateTime<Utc>,
+        updated: DateTime<Utc>,
+        expire: Option<DateTime<Utc>>,
+    }
+    impl From<database::Command> for Command {
+        fn from(command: database::Command) -> Command {
+            Command {
+                id: command.command_id,
+                device: command.device_pubkey.to_base58(),
+                properties: command.command_properties,
+                status: command.command_status,
+                message: command.command_message,
+                created: command.command_created,
+                updated: command.command_updated,
+                expire: command.command_expire,
+            }
+        }
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { id }) = req.body_json().await {
+        if let Some(command) = db_get_command(&username, id).await? {
+            ApiResult::success("", Command::from(command)).into()
+        } else {
+            ApiResult::failure("Command not found", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+pub async fn list_retention(req: Request<()>) -> tide::Result {
+    #[derive(Serialize)]
+    struct Policy {
+        id: i64,
+        device: Option<String>,
+        property: Option<String>,
+        raw_retention: i64,
+        rollup_retention: i64,
+    }
+    impl From<database::RetentionPolicy> for Policy {
+        fn from(policy: database::RetentionPolicy) -> Policy {
+            Policy {
+                id: policy.policy_id,
+                device: policy.device_pubkey.map(|d| d.to_base58()),
+                property: policy.property_name,
+                raw_retention: policy.policy_raw_secs,
+                rollup_retention: policy.policy_rollup_secs,
+            }
+        }
+    }
+    let policies = db_get_retention_policies(&account(&req).account_username).await?;
+    let policies: Vec<Policy> = policies.into_iter().map(|p| p.into()).collect();
+    ApiResult::success("", policies).into()
+}
+/// Sets a retention policy. Policies only ever apply to devices the caller
+/// owns, so device-scoped ones are refused on shared devices.
+pub async fn set_retention(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: Option<String>,
+        property: Option<String>,
+        raw_retention: i64,
+        rollup_retention: i64,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input {
+        device,
+        property,
+        raw_retention,
+        rollup_retention,
+    }) = req.body_json().await
+    {
+        if raw_retention <= 0 || rollup_retention <= 0 {
+            return ApiResult::failure("Retention must be positive", ()).into();
+        } else if rollup_retention < raw_retention {
+            return ApiResult::failure("Rollup retention must not be shorter than raw", ()).into();
+        }
+        let pubkey = match device.map(|d| d.from_base58()) {
+            Some(Ok(pubkey)) => Some(pubkey),
+            Some(Err(_)) => return ApiResult::failure("Invalid Input", ()).into(),
+            None => None,
+        };
+        if property.is_some() && pubkey.is_none() {
+            return ApiResult::failure("Property policy requires a device", ()).into();
+        }
+        if let Some(pubkey) = &pubkey {
+            match db_get_device(&username, pubkey).await? {
+                None => return ApiResult::failure("Device not found", ()).into(),
+                Some(device) if !device.link_owner => {
+                    return ApiResult::failure("Permission denied", ()).into()
+                }
+                _ => {}
+            }
+        }
+        let id = db_set_retention_policy(
+            &username,
+            pubkey.as_deref(),
+            property.as_deref(),
+            raw_retention,
+            rollup_retention,
+        )
+        .await?;
+        ApiResult::success("", id).into()
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn delete_retention(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        id: i64,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { id }) = req.body_json().await {
+        if db_delete_retention_policy(&username, id).await? {
+            ApiResult::success("", ()).into()
+        } else {
+            ApiResult::failure("Policy not found", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn list_schema_versions(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            let versions = db_get_schema_versions(&username, &pubkey).await?;
+            ApiResult::success("", versions).into()
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn diff_schema_versions(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        from: i32,
+        to: i32,
+    }
+    #[derive(Serialize, Default)]
+    struct Diff {
+        added: Vec<String>,
+        removed: Vec<String>,
+        changed: Vec<String>,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device, from, to }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            let from = db_get_schema_version(&username, &pubkey, from).await?;
+            let to = db_get_schema_version(&username, &pubkey, to).await?;
+            if let (Some(from), Some(to)) = (from, to) {
+                let empty = serde_json::Map::new();
+                let properties = |schema: &Value| {
+                    schema
+                        .get("properties")
+                        .and_then(Value::as_object)
+                        .cloned()
+                        .unwrap_or_else(|| empty.clone())
+                };
+                let (from, to) = (properties(&from.schema_value), properties(&to.schema_value));
+                let mut diff = Diff::default();
+                for (name, property) in &to {
+                    match from.get(name) {
+                        None => diff.added.push(name.clone()),
+                        Some(old) if old != property => diff.changed.push(name.clone()),
+                        _ => {}
+                    }
+                }
+                diff.removed = from
+                    .keys()
+                    .filter(|name| !to.contains_key(*name))
+                    .cloned()
+                    .collect();
+                ApiResult::success("", diff).into()
+            } else {
+                ApiResult::failure("Schema version not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn set_archive_dropped(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        enabled: bool,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device, enabled }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                db_device_archive_dropped(&username, &pubkey, enabled).await?;
+                ApiResult::success("", ()).into()
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn list_archived_properties(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            let properties = db_get_archived_properties(&username, &pubkey).await?;
+            ApiResult::success("", properties).into()
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn list_property_grant(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { device }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&username, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                let grants = db_get_property_grants(&pubkey).await?;
+                ApiResult::success("", grants).into()
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn set_property_grant(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        username: String,
+        property: String,
+        read: bool,
+        write: bool,
+    }
+
+    let owner = account(&req).account_username;
+    if let Ok(Input {
+        device,
+        username,
+        property,
+        read,
+        write,
+    }) = req.body_json().await
+    {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&owner, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                if db_get_account(&username).await?.is_none() {
+                    return ApiResult::failure("Account not found", ()).into();
+                }
+                db_set_property_grant(&username, &pubkey, &property, read, write).await?;
+                ApiResult::success("", ()).into()
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn delete_property_grant(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        username: String,
+        property: String,
+    }
+
+    let owner = account(&req).account_username;
+    if let Ok(Input {
+        device,
+        username,
+        property,
+    }) = req.body_json().await
+    {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&owner, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                if db_delete_property_grant(&username, &pubkey, &property).await? {
+                    ApiResult::success("", ()).into()
+                } else {
+                    ApiResult::failure("Grant not found", ()).into()
+                }
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+/// Sharing, revoking and transferring act on `link_account_device` rows, so
+/// they are left to direct owners rather than those inheriting ownership.
+const INHERITED_OWNER: &str = "Ownership is inherited; ask a direct owner";
+
+pub async fn share_device(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum ShareRole {
+        Owner,
+        Viewer,
+    }
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        username: String,
+        role: ShareRole,
+    }
+
+    let owner = account(&req).account_username;
+    if let Ok(Input {
+        device,
+        username,
+        role,
+    }) = req.body_json().await
+    {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&owner, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                if !db_is_direct_owner(&owner, &pubkey).await? {
+                    return ApiResult::failure(INHERITED_OWNER, ()).into();
+                }
+                if db_get_account(&username).await?.is_none() {
+                    return ApiResult::failure("Account not found", ()).into();
+                }
+                if db_share_device(&username, &pubkey, role == ShareRole::Owner).await? {
+                    ApiResult::success("", ()).into()
+                } else {
+                    ApiResult::failure("Cannot demote the last owner", ()).into()
+                }
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn revoke_device(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        username: String,
+    }
+
+    let owner = account(&req).account_username;
+    if let Ok(Input { device, username }) = req.body_json().await {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&owner, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                if !db_is_direct_owner(&owner, &pubkey).await? {
+                    return ApiResult::failure(INHERITED_OWNER, ()).into();
+                }
+                if db_revoke_device(&username, &pubkey).await? {
+                    ApiResult::success("", ()).into()
+                } else {
+                    ApiResult::failure("Cannot revoke the last owner", ()).into()
+                }
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn transfer_device(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        device: String,
+        username: String,
+        #[serde(default)]
+        keep_access: bool,
+    }
+
+    let owner = account(&req).account_username;
+    if let Ok(Input {
+        device,
+        username,
+        keep_access,
+    }) = req.body_json().await
+    {
+        if let Ok(pubkey) = device.from_base58() {
+            if let Some(device) = db_get_device(&owner, &pubkey).await? {
+                if !device.link_owner {
+                    return ApiResult::failure("Permission denied", ()).into();
+                }
+                if !db_is_direct_owner(&owner, &pubkey).await? {
+                    return ApiResult::failure(INHERITED_OWNER, ()).into();
+                }
+                if username == owner {
+                    return ApiResult::failure("Already the owner", ()).into();
+                }
+                if db_get_account(&username).await?.is_none() {
+                    return ApiResult::failure("Account not found", ()).into();
+                }
+                db_transfer_device(&owner, &username, &pubkey, keep_access).await?;
+                ApiResult::success("", ()).into()
+            } else {
+                ApiResult::failure("Device not found", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid Input", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+pub async fn set_role(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        username: String,
+        role: Role,
+    }
+
+    let caller = account(&req);
+    if let Ok(Input { username, role }) = req.body_json().await {
+        if let Some(target) = db_get_account(&username).await? {
+            let assignable = if caller.role() == Role::Superadmin {
+                Role::Superadmin
+            } else {
+                Role::User
+            };
+            if caller.account_username == username
+                || role > assignable
+                || target.role() > assignable
+                || !manages(&caller, &username).await?
+            {
+                ApiResult::failure("Permission denied", ()).into()
+            } else {
+                db_set_account_role(&username, role).await?;
+                ApiResult::success("Success", ()).into()
+            }
+        } else {
+            ApiResult::failure("Account not found", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+/// Clears the failed attempts against an account, lifting its lockout.
+pub async fn unlock_account(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        username: String,
+    }
+
+    let caller = account(&req);
+    if let Ok(Input { username }) = req.body_json().await {
+        if db_get_account(&username).await?.is_none() {
+            ApiResult::failure("Account not found", ()).into()
+        } else if caller.account_username == username || !manages(&caller, &username).await? {
+            ApiResult::failure("Permission denied", ()).into()
+        } else {
+            db_clear_login_failures(&format!("account:{}", username)).await?;
+            ApiResult::success("Success", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+/// Starts TOTP enrollment with a fresh secret; it takes effect once
+/// [`confirm_totp`] sees a code generated from it.
+pub async fn enroll_totp(req: Request<()>) -> tide::Result {
+    #[derive(Serialize)]
+    struct Output {
+        secret: String,
+        uri: String,
+    }
+
+    let username = account(&req).account_username;
+    if totp_secret(&username).await?.is_some() {
+        ApiResult::failure("Two-factor authentication already enabled", ()).into()
+    } else {
+        let secret = totp::generate_secret();
+        db_set_totp_secret(&username, Some(&secret)).await?;
+        let output = Output {
+            secret: totp::base32(&secret),
+            uri: totp::provisioning_uri(&username, &secret),
+        };
+        ApiResult::success("", output).into()
+    }
+}
+
+/// Enables TOTP and hands out the recovery codes, which are shown only once.
+pub async fn confirm_totp(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        code: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { code }) = req.body_json().await {
+        match db_get_totp(&username).await? {
+            Some(Totp {
+                account_totp_enabled: true,
+                ..
+            }) => ApiResult::failure("Two-factor authentication already enabled", ()).into(),
+            Some(Totp {
+                account_totp_secret: Some(secret),
+                ..
+            }) => {
+                let valid = match totp::verify(&secret, &code) {
+                    Some(step) => db_use_totp_step(&username, step).await?,
+                    None => false,
+                };
+                if valid {
+                    let codes = recovery_codes();
+                    db_enable_totp(&username).await?;
+                    db_set_recovery_codes(&username, &codes).await?;
+                    ApiResult::success("Success", codes).into()
+                } else {
+                    ApiResult::failure("Invalid two-factor code", ()).into()
+                }
+            }
+            _ => ApiResult::failure("Two-factor authentication not enrolled", ()).into(),
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+pub async fn disable_totp(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        password: String,
+        code: String,
+    }
+
+    let account = account(&req);
+    let username = account.account_username.clone();
+    if let Ok(Input { password, code }) = req.body_json().await {
+        let key = format!("account:{}", username);
+        if let Some(until) = db_throttle_until(std::slice::from_ref(&key)).await? {
+            ApiResult::failure(THROTTLED, until).into()
+        } else if let Some(secret) = totp_secret(&username).await? {
+            if account.valid_password(&password) && second_factor(&username, &secret, &code).await?
+            {
+                db_clear_login_failures(&key).await?;
+                db_set_totp_secret(&username, None).await?;
+                ApiResult::success("Success", ()).into()
+            } else {
+                db_record_login_failure(&key, backoff).await?;
+                ApiResult::failure("Invalid password or two-factor code", ()).into()
+            }
+        } else {
+            ApiResult::failure("Two-factor authentication not enabled", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+/// Replaces all recovery codes, e.g. after some were used up.
+pub async fn new_recovery_codes(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        code: String,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { code }) = req.body_json().await {
+        if let Some(secret) = totp_secret(&username).await? {
+            if second_factor(&username, &secret, &code).await? {
+                let codes = recovery_codes();
+                db_set_recovery_codes(&username, &codes).await?;
+                ApiResult::success("Success", codes).into()
+            } else {
+                ApiResult::failure("Invalid two-factor code", ()).into()
+            }
+        } else {
+            ApiResult::failure("Two-factor authentication not enabled", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+/// Sets or, with `null`, clears where notifications such as password
+/// resets are sent.
+pub async fn set_email(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        email: Option<String>,
+    }
+
+    let username = account(&req).account_username;
+    if let Ok(Input { email }) = req.body_json().await {
+        if email
+            .as_ref()
+            .is_some_and(|email| email.parse::<lettre::Address>().is_err())
+        {
+            ApiResult::failure("Invalid email address", ()).into()
+        } else {
+            db_set_account_email(&username, email.as_deref()).await?;
+            ApiResult::success("Success", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+/// Mails a reset token to the account's email address. Answers the same
+/// whether or not the account exists or has an address. Every request counts
+/// against the account and the caller's IP like a failed login, under keys
+/// of their own so that resets cannot lock anyone out of logging in.
+pub async fn request_password_reset(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        username: String,
+    }
+
+    let ip = client_ip(&req);
+    if let Ok(Input { username }) = req.body_json().await {
+        let keys = [
+            format!("reset:account:{}", username),
+            format!("reset:ip:{}", ip),
+        ];
+        if let Some(until) = db_throttle_until(&keys).await? {
+            return ApiResult::failure(THROTTLED, until).into();
+        }
+        for key in &keys {
+            db_record_login_failure(key, backoff).await?;
+        }
+        let account = db_get_account(&username).await?;
+        let email = db_get_account_email(&username).await?;
+        if let (Some(account), Some(email)) = (account, email) {
+            if !account.account_disabled {
+                let reset = db_create_password_reset(&username).await?;
+                let body = format!(
+                    "A password reset was requested for account `{}`.\n\n\
+                    Reset token: {}\n\n\
+                    The token can be used once and expires at {}. If you did not ask \
+                    for this, you can ignore this message.",
+                    username,
+                    reset.token.to_base58(),
+                    reset.expire.to_rfc3339()
+                );
+                async_std::task::spawn(async move {
+                    if let Err(e) = notify::send(&email, "Password reset", &body).await {
+                        tide::log::error!("failed to send password reset: {}", e);
+                    }
+                });
+            }
+        }
+        ApiResult::success(
+            "If the account has an email address, a reset token was sent",
+            (),
+        )
+        .into()
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+pub async fn confirm_password_reset(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        token: String,
+        new_password: String,
+    }
+
+    if let Ok(Input {
+        token,
+        new_password,
+    }) = req.body_json().await
+    {
+        let reset = match token.trim().from_base58() {
+            Ok(token) => db_get_password_reset_account(&token)
+                .await?
+                .map(|username| (token, username)),
+            Err(_) => None,
+        };
+        if let Some((token, username)) = reset {
+            if let Err(e) = password::check(&username, &new_password) {
+                ApiResult::failure(&e, ()).into()
+            } else if db_reset_password(&token, &new_password).await? {
+                ApiResult::success("Success", ()).into()
+            } else {
+                ApiResult::failure("Invalid or expired token", ()).into()
+            }
+        } else {
+            ApiResult::failure("Invalid or expired token", ()).into()
+        }
+    } else {
+        ApiResult::failure("Invalid Input", ()).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_then_locks_out() {
+        for failures in 0..=FREE_ATTEMPTS {
+            assert_eq!(backoff(failures), std::time::Duration::ZERO);
+        }
+        assert_eq!(
+            backoff(FREE_ATTEMPTS + 1),
+            std::time::Duration::from_secs(1)
+        );
+        assert_eq!(
+            backoff(FREE_ATTEMPTS + 2),
+            std::time::Duration::from_secs(2)
+        );
+        assert_eq!(
+            backoff(FREE_ATTEMPTS + 3),
+            std::time::Duration::from_secs(4)
+        );
+        for failures in FREE_ATTEMPTS + 1..LOCKOUT_ATTEMPTS {
+            assert!(backoff(failures) < LOCKOUT);
+            assert!(backoff(failures) < backoff(failures + 1));
+        }
+        assert_eq!(backoff(LOCKOUT_ATTEMPTS), LOCKOUT);
+        assert_eq!(backoff(i32::MAX), LOCKOUT);
+    }
+}
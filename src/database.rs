@@ -1,62 +1,168 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
 use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
-    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::rand_core::{OsRng, RngCore},
+    PasswordHash,
 };
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use sqlx::{query, query_as};
 
+use crate::password;
+
 pub static DB: Lazy<sqlx::PgPool> = Lazy::new(|| {
     let url = std::env::var("DATABASE_URL").expect("set DATABASE_URL to your postgres uri");
     sqlx::PgPool::connect_lazy(&url).unwrap()
 });
 
+/// Username of the bootstrap superadmin, from `ADMIN_USERNAME`.
+pub static ADMIN: Lazy<String> =
+    Lazy::new(|| std::env::var("ADMIN_USERNAME").unwrap_or_else(|_| "admin".to_string()));
+
+const DEFAULT_ADMIN_PASSWORD: &str = "admin";
+
 pub async fn migrate() -> Result<(), sqlx::migrate::MigrateError> {
     sqlx::migrate!("./migrations").run(&*DB).await?;
+    Ok(())
+}
 
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password("admin".as_bytes(), &salt)
-        .unwrap()
-        .to_string();
-    query!(
-        r#"
-        insert into account(account_username, account_password, account_name)
-        values ('admin', $1, 'Admin')
-        on conflict do nothing;
-        "#,
-        password_hash
-    )
-    .execute(&*DB)
-    .await?;
+/// Creates the bootstrap superadmin if it does not exist yet.
+///
+/// Its password comes from `ADMIN_PASSWORD_HASH` (an Argon2 PHC string) or
+/// `ADMIN_PASSWORD`, falling back to `admin`, and must be changed on first
+/// login. An existing admin still on the default password is given the
+/// configured one instead. Unless `insecure_dev` is set, refuses to go on
+/// while any superadmin still has the default password.
+pub async fn bootstrap_admin(insecure_dev: bool) -> Result<()> {
+    let password_hash = if let Ok(hash) = std::env::var("ADMIN_PASSWORD_HASH") {
+        PasswordHash::new(&hash).map_err(|e| anyhow!("Invalid ADMIN_PASSWORD_HASH: {}", e))?;
+        hash
+    } else {
+        let password =
+            std::env::var("ADMIN_PASSWORD").unwrap_or_else(|_| DEFAULT_ADMIN_PASSWORD.to_string());
+        password::hash(&password)?
+    };
+
+    match db_get_account(&ADMIN).await? {
+        Some(admin) => {
+            if admin.valid_password(DEFAULT_ADMIN_PASSWORD)
+                && !password::verify(&password_hash, DEFAULT_ADMIN_PASSWORD)
+            {
+                query!(
+                    r#"update account
+                        set account_password = $2, account_must_change_password = true
+                        where account_username = $1"#,
+                    &*ADMIN,
+                    password_hash
+                )
+                .execute(&*DB)
+                .await?;
+            }
+        }
+        None => {
+            query!(
+                r#"
+                insert into account(account_username, account_password, account_name,
+                    account_role, account_must_change_password)
+                values ($1, $2, 'Admin', 'superadmin', true)
+                on conflict do nothing;
+                "#,
+                &*ADMIN,
+                password_hash
+            )
+            .execute(&*DB)
+            .await?;
+        }
+    }
+
+    if !insecure_dev {
+        let superadmins = query_as!(
+            Account,
+            r#"select account_username, account_name, account_password, account_disabled,
+                    account_role, account_must_change_password
+                from account
+                where account_role = 'superadmin'"#
+        )
+        .fetch_all(&*DB)
+        .await?;
+        if let Some(account) = superadmins
+            .iter()
+            .find(|account| account.valid_password(DEFAULT_ADMIN_PASSWORD))
+        {
+            bail!(
+                "Superadmin `{}` still has the default password; set ADMIN_PASSWORD or \
+                ADMIN_PASSWORD_HASH, change its password, or pass --insecure-dev",
+                account.account_username
+            );
+        }
+    }
     Ok(())
 }
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct Account {
     pub account_name: String,
     pub account_username: String,
+    #[serde(skip_serializing)]
     pub account_password: String,
+    pub account_disabled: bool,
+    pub account_role: String,
+    pub account_must_change_password: bool,
+}
+
+/// Account roles, from least to most privileged.
+///
+/// - `Viewer` can only read what its account is linked to.
+/// - `User` also controls its devices and manages its sub-accounts.
+/// - `Installer` also onboards devices for accounts below it and hands out
+///   roles up to `User` there.
+/// - `Superadmin` manages every account and assigns any role.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    User,
+    Installer,
+    Superadmin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::User => "user",
+            Role::Installer => "installer",
+            Role::Superadmin => "superadmin",
+        }
+    }
 }
 
 impl Account {
+    pub fn role(&self) -> Role {
+        match self.account_role.as_str() {
+            "superadmin" => Role::Superadmin,
+            "installer" => Role::Installer,
+            "user" => Role::User,
+            _ => Role::Viewer,
+        }
+    }
     pub fn valid_password(&self, password: &str) -> bool {
-        let parsed_hash = PasswordHash::new(&self.account_password).unwrap();
-        Argon2::default()
-            .verify_password(password.as_bytes(), &parsed_hash)
-            .is_ok()
+        password::verify(&self.account_password, password)
     }
 }
 
 #[derive(Serialize)]
 pub struct Device {
     pub device_pubkey: Vec<u8>,
-    pub device_accepted: bool,
+    pub device_status: String,
     pub device_title: String,
     pub device_local_ip: String,
     pub device_schema: Value,
+    pub link_owner: bool,
+    pub link_all_property: bool,
 }
 pub async fn db_get_property(
     username: &str,
@@ -66,9 +172,10 @@ pub async fn db_get_property(
     Ok(sqlx::query!(
         r#"
                 select property_value from property 
-                join link_account_device 
-                on link_account_device.device_pubkey = property.device_pubkey
-                where account_username = $1 and property.device_pubkey = $2 and property_name = $3
+                join account_devices($1) as account_device
+                on account_device.device_pubkey = property.device_pubkey
+                where property.device_pubkey = $2 and property_name = $3
+                    and property_readable($1, $2, $3)
             "#,
         username,
         device,
@@ -78,14 +185,123 @@ pub async fn db_get_property(
     .await?
     .map(|o| o.property_value))
 }
+#[derive(Serialize)]
+pub struct PropertyRecord {
+    pub property_value: Value,
+    pub property_time: DateTime<Utc>,
+}
+pub async fn db_get_property_history(
+    username: &str,
+    device: &[u8],
+    property_id: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: i64,
+    ascending: bool,
+) -> Result<Vec<PropertyRecord>> {
+    Ok(query_as!(
+        PropertyRecord,
+        r#"
+            select property_value, property_time from property_history
+            join account_devices($1) as account_device
+            on account_device.device_pubkey = property_history.device_pubkey
+            where property_history.device_pubkey = $2
+                and property_name = $3 and property_readable($1, $2, $3)
+                and ($4::timestamptz is null or property_time >= $4)
+                and ($5::timestamptz is null or property_time < $5)
+            order by
+                case when $7 then property_time end asc,
+                case when not $7 then property_time end desc
+            limit $6
+            "#,
+        username,
+        device,
+        property_id,
+        from,
+        to,
+        limit,
+        ascending
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+#[derive(Serialize)]
+pub struct PropertyBucket {
+    pub bucket_time: DateTime<Utc>,
+    pub bucket_min: f64,
+    pub bucket_max: f64,
+    pub bucket_avg: f64,
+    pub bucket_count: i64,
+    pub bucket_last: f64,
+}
+/// Aggregates numeric history values into `bucket_secs` wide buckets.
+#[allow(clippy::too_many_arguments)]
+pub async fn db_get_property_buckets(
+    username: &str,
+    device: &[u8],
+    property_id: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    bucket_secs: f64,
+    limit: i64,
+    ascending: bool,
+) -> Result<Vec<PropertyBucket>> {
+    Ok(query_as!(
+        PropertyBucket,
+        r#"
+            select
+                bucket_time as "bucket_time!",
+                bucket_min as "bucket_min!",
+                bucket_max as "bucket_max!",
+                bucket_avg as "bucket_avg!",
+                bucket_count as "bucket_count!",
+                bucket_last as "bucket_last!"
+            from (
+                select
+                    date_bin(make_interval(secs => $6), property_time, 'epoch') as bucket_time,
+                    min(value) as bucket_min,
+                    max(value) as bucket_max,
+                    avg(value) as bucket_avg,
+                    count(*) as bucket_count,
+                    (array_agg(value order by property_time desc))[1] as bucket_last
+                from (
+                    select property_time, (property_value #>> '{}')::float8 as value
+                    from property_history
+                    join account_devices($1) as account_device
+                    on account_device.device_pubkey = property_history.device_pubkey
+                    where property_history.device_pubkey = $2
+                        and property_name = $3 and property_readable($1, $2, $3)
+                        and ($4::timestamptz is null or property_time >= $4)
+                        and ($5::timestamptz is null or property_time < $5)
+                        and json_typeof(property_value) = 'number'
+                ) as history
+                group by bucket_time
+            ) as buckets
+            order by
+                case when $8 then bucket_time end asc,
+                case when not $8 then bucket_time end desc
+            limit $7
+            "#,
+        username,
+        device,
+        property_id,
+        from,
+        to,
+        bucket_secs,
+        limit,
+        ascending
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
 pub async fn db_get_device_by_username(username: &str) -> Result<Vec<Device>> {
     Ok(query_as!(
         Device,
         r#"
-            select device.device_pubkey, device_accepted, device_title, device_local_ip, device_schema
-            from device join link_account_device
-            on link_account_device.device_pubkey = device.device_pubkey
-            where account_username = $1
+            select device.device_pubkey, device_status, device_title, device_local_ip, device_schema,
+                link_owner as "link_owner!", link_all_property as "link_all_property!"
+            from device join account_devices($1) as account_device
+            on account_device.device_pubkey = device.device_pubkey
             "#,
         username
     )
@@ -97,10 +313,11 @@ pub async fn db_get_device(username: &str, pubkey: &[u8]) -> Result<Option<Devic
     Ok(query_as!(
         Device,
         r#"
-            select device.device_pubkey, device_accepted, device_title, device_local_ip, device_schema
-            from device join link_account_device
-            on link_account_device.device_pubkey = device.device_pubkey
-            where account_username = $1 and device.device_pubkey= $2
+            select device.device_pubkey, device_status, device_title, device_local_ip, device_schema,
+                link_owner as "link_owner!", link_all_property as "link_all_property!"
+            from device join account_devices($1) as account_device
+            on account_device.device_pubkey = device.device_pubkey
+            where device.device_pubkey= $2
             "#,
         username,
         pubkey
@@ -109,22 +326,38 @@ pub async fn db_get_device(username: &str, pubkey: &[u8]) -> Result<Option<Devic
     .await?)
 }
 
-pub async fn db_accept_device(username: &str, pubkey: &[u8]) -> Result<()> {
+/// Moves a device to `status`; rejecting or decommissioning it also drops its
+/// queued commands.
+pub async fn db_set_device_status(username: &str, pubkey: &[u8], status: &str) -> Result<()> {
+    let mut tx = DB.begin().await?;
     query!(
         r#"
         update device 
-        set device_accepted = true 
+        set device_status = $3 
         where device_pubkey = $2 and 0 < (
             select count(*) from device
-            join link_account_device
-            on link_account_device.device_pubkey = device.device_pubkey
-            where account_username = $1 and device.device_pubkey = $2
+            join account_devices($1) as account_device
+            on account_device.device_pubkey = device.device_pubkey
+            where device.device_pubkey = $2 and link_owner
             )"#,
         username,
-        pubkey
+        pubkey,
+        status
     )
-    .execute(&*DB)
+    .execute(&mut tx)
     .await?;
+    if status == "rejected" || status == "decommissioned" {
+        query!(
+            r#"
+            delete from command
+            where device_pubkey = $1 and command_status = 'queued'
+            "#,
+            pubkey
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
     Ok(())
 }
 pub async fn db_device_new_title(username: &str, pubkey: &[u8], title: &str) -> Result<()> {
@@ -134,9 +367,9 @@ pub async fn db_device_new_title(username: &str, pubkey: &[u8], title: &str) ->
         set device_title = $3 
         where device_pubkey = $2 and 0 < (
             select count(*) from device
-            join link_account_device
-            on link_account_device.device_pubkey = device.device_pubkey
-            where account_username = $1 and device.device_pubkey = $2
+            join account_devices($1) as account_device
+            on account_device.device_pubkey = device.device_pubkey
+            where device.device_pubkey = $2 and link_owner
             )"#,
         username,
         pubkey,
@@ -152,12 +385,7 @@ pub async fn db_create_account(
     owner: &str,
     name: &str,
 ) -> Result<Account> {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
-        .to_string();
+    let password_hash = password::hash(password)?;
 
     query!(
         r#"
@@ -173,10 +401,11 @@ pub async fn db_create_account(
     query!(
         r#"
             insert into link_account_account (account_username, derive_account_username)
-            values ('admin', $1)
+            values ($2, $1)
             on conflict do nothing;
             "#,
-        username
+        username,
+        &*ADMIN
     )
     .execute(&*DB)
     .await?;
@@ -194,24 +423,31 @@ pub async fn db_create_account(
     Ok(Account {
         account_name: username.to_string(),
         account_username: username.to_string(),
-        account_password: password.to_string(),
+        account_password: password_hash,
+        account_disabled: false,
+        account_role: Role::User.as_str().to_string(),
+        account_must_change_password: false,
     })
 }
 //pub async fn new_with_owner(username: &str, password: &str, owner: &str) -> Result<()> {
 
 //}
 pub async fn db_get_all_account(owner: &str) -> Result<Vec<Account>> {
-    Ok(query_as!(Account,
-            r#"
-                select account.account_username, account.account_name, account.account_password from account
+    Ok(query_as!(
+        Account,
+        r#"
+                select account.account_username, account.account_name, account.account_password,
+                    account.account_disabled, account.account_role,
+                    account.account_must_change_password
+                from account
                 join link_account_account
                 on link_account_account.derive_account_username = account.account_username
                 where link_account_account.account_username = $1
                 "#,
-            owner
-        )
-        .fetch_all(&*DB)
-        .await?)
+        owner
+    )
+    .fetch_all(&*DB)
+    .await?)
     //.into_iter()
     //.map(|o| Account {
     //    account_name: o.account_name,
@@ -220,10 +456,35 @@ pub async fn db_get_all_account(owner: &str) -> Result<Vec<Account>> {
     //})
     //.collect())
 }
+/// Every account below `owner` in the account tree.
+pub async fn db_get_descendant_accounts(owner: &str) -> Result<Vec<Account>> {
+    Ok(query_as!(
+        Account,
+        r#"
+            with recursive descendant(account_username) as (
+                select derive_account_username from link_account_account
+                where account_username = $1
+                union
+                select derive_account_username from link_account_account
+                join descendant
+                on link_account_account.account_username = descendant.account_username
+            )
+            select account.account_username, account_name, account_password, account_disabled,
+                account_role, account_must_change_password
+            from account join descendant
+            on descendant.account_username = account.account_username
+            "#,
+        owner
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
 pub async fn db_get_account(username: &str) -> Result<Option<Account>> {
     Ok(query_as!(
         Account,
-        r#"select * from account
+        r#"select account_username, account_name, account_password, account_disabled,
+                account_role, account_must_change_password
+            from account
             where account_username = $1"#,
         username
     )
@@ -236,14 +497,1188 @@ pub async fn db_get_account(username: &str) -> Result<Option<Account>> {
     //}))
 }
 pub async fn db_change_password(username: &str, password: &str) -> Result<()> {
+    query!(
+        r#"update account
+            set account_password = $2, account_must_change_password = false
+            where account_username = $1"#,
+        username,
+        password::hash(password)?
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+/// Re-hashes a verified `password` with the current parameters, leaving
+/// the rest of the account alone.
+pub async fn db_rehash_password(username: &str, password: &str) -> Result<()> {
     query!(
         r#"update account
             set account_password = $2
             where account_username = $1"#,
         username,
-        password
+        password::hash(password)?
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Session {
+    pub session_token: Vec<u8>,
+    pub account_username: String,
+    pub session_expire: DateTime<Utc>,
+}
+
+/// Starts a session for `username`; only the SHA-256 of its token is stored.
+pub async fn db_create_session(username: &str) -> Result<Session> {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    let hash = Sha256::digest(token);
+
+    query!(
+        r#"delete from session
+            where session_expire < now()"#
+    )
+    .execute(&*DB)
+    .await?;
+    let session = query!(
+        r#"
+            insert into session (session_hash, account_username, session_expire)
+            values ($1, $2, now() + interval '7 days')
+            returning account_username, session_expire
+            "#,
+        hash.as_slice(),
+        username
+    )
+    .fetch_one(&*DB)
+    .await?;
+    Ok(Session {
+        session_token: token.to_vec(),
+        account_username: session.account_username,
+        session_expire: session.session_expire,
+    })
+}
+pub async fn db_get_session_account(token: &[u8]) -> Result<Option<Account>> {
+    let hash = Sha256::digest(token);
+    Ok(query_as!(
+        Account,
+        r#"
+            select account.account_username, account_password, account_name, account_disabled,
+                account_role, account_must_change_password
+            from account join session
+            on session.account_username = account.account_username
+            where session_hash = $1 and session_expire > now() and not account_disabled
+            "#,
+        hash.as_slice()
+    )
+    .fetch_optional(&*DB)
+    .await?)
+}
+pub async fn db_delete_session(token: &[u8]) -> Result<()> {
+    let hash = Sha256::digest(token);
+    query!(
+        r#"delete from session
+            where session_hash = $1"#,
+        hash.as_slice()
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+pub async fn db_delete_all_session(username: &str) -> Result<()> {
+    query!(
+        r#"delete from session
+            where account_username = $1"#,
+        username
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Command {
+    pub command_id: i64,
+    pub device_pubkey: Vec<u8>,
+    pub command_properties: Value,
+    pub command_status: String,
+    pub command_message: Option<String>,
+    pub command_created: DateTime<Utc>,
+    pub command_updated: DateTime<Utc>,
+    pub command_expire: Option<DateTime<Utc>>,
+}
+
+pub async fn db_expire_commands(device: &[u8]) -> Result<()> {
+    query!(
+        r#"
+        update command
+        set command_status = 'expired', command_updated = now()
+        where device_pubkey = $1 and command_status = 'queued' and command_expire <= now()
+        "#,
+        device
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+/// How long finished commands stay around for status lookups.
+const COMMAND_KEEP: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Deletes acked, failed and expired commands finished more than
+/// [`COMMAND_KEEP`] ago, along with queued ones that expired that long ago.
+pub async fn db_prune_commands() -> Result<()> {
+    query!(
+        r#"
+        delete from command
+        where (command_status in ('acked', 'failed', 'expired')
+                and command_updated < now() - make_interval(secs => $1))
+            or (command_status = 'queued'
+                and command_expire < now() - make_interval(secs => $1))
+        "#,
+        COMMAND_KEEP.as_secs_f64()
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+/// A command visible to `username`: anyone who can read the whole device, or
+/// who holds write grants on every property the command sets.
+pub async fn db_get_command(username: &str, id: i64) -> Result<Option<Command>> {
+    query!(
+        r#"
+        update command
+        set command_status = 'expired', command_updated = now()
+        where command_id = $1 and command_status = 'queued' and command_expire <= now()
+        "#,
+        id
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(query_as!(
+        Command,
+        r#"
+            select command_id, command.device_pubkey, command_properties, command_status,
+                command_message, command_created, command_updated, command_expire
+            from command join account_devices($1) as account_device
+            on account_device.device_pubkey = command.device_pubkey
+            where command_id = $2
+                and (link_owner or link_all_property or not exists (
+                    select 1 from json_object_keys(command_properties) as command_key(name)
+                    where not exists (
+                        select 1 from property_grant
+                        where property_grant.account_username = $1
+                            and property_grant.device_pubkey = command.device_pubkey
+                            and property_grant.property_name = command_key.name
+                            and grant_write
+                    )
+                ))
+            "#,
+        username,
+        id
+    )
+    .fetch_optional(&*DB)
+    .await?)
+}
+
+#[derive(Serialize)]
+pub struct RetentionPolicy {
+    pub policy_id: i64,
+    pub device_pubkey: Option<Vec<u8>>,
+    pub property_name: Option<String>,
+    pub policy_raw_secs: i64,
+    pub policy_rollup_secs: i64,
+}
+
+pub async fn db_get_retention_policies(username: &str) -> Result<Vec<RetentionPolicy>> {
+    Ok(query_as!(
+        RetentionPolicy,
+        r#"
+            select policy_id, device_pubkey, property_name, policy_raw_secs, policy_rollup_secs
+            from retention_policy
+            where account_username = $1
+            order by policy_id
+            "#,
+        username
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+pub async fn db_set_retention_policy(
+    username: &str,
+    device: Option<&[u8]>,
+    property_id: Option<&str>,
+    raw_secs: i64,
+    rollup_secs: i64,
+) -> Result<i64> {
+    Ok(query!(
+        r#"
+            insert into retention_policy
+                (account_username, device_pubkey, property_name, policy_raw_secs, policy_rollup_secs)
+            values ($1, $2, $3, $4, $5)
+            on conflict (account_username, coalesce(device_pubkey, ''::bytea), coalesce(property_name, ''))
+            do update
+            set policy_raw_secs = $4, policy_rollup_secs = $5
+            returning policy_id
+            "#,
+        username,
+        device,
+        property_id,
+        raw_secs,
+        rollup_secs
+    )
+    .fetch_one(&*DB)
+    .await?
+    .policy_id)
+}
+pub async fn db_delete_retention_policy(username: &str, id: i64) -> Result<bool> {
+    Ok(query!(
+        r#"
+            delete from retention_policy
+            where account_username = $1 and policy_id = $2
+            "#,
+        username,
+        id
     )
     .execute(&*DB)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Rolls raw history past its retention into hourly aggregates, then drops
+/// raw rows and aggregates that have outlived their policy.
+pub async fn db_enforce_retention() -> Result<()> {
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"
+        insert into property_rollup (device_pubkey, property_name, rollup_time,
+            rollup_min, rollup_max, rollup_avg, rollup_count, rollup_last)
+        select device_pubkey, property_name, date_trunc('hour', property_time),
+            min(value), max(value), avg(value), count(*),
+            (array_agg(value order by property_time desc))[1]
+        from (
+            select property_history.device_pubkey, property_history.property_name, property_time,
+                (property_value #>> '{}')::float8 as value
+            from property_history
+            join effective_retention
+            on effective_retention.device_pubkey = property_history.device_pubkey
+                and effective_retention.property_name = property_history.property_name
+            where json_typeof(property_value) = 'number'
+                and property_time < date_trunc('hour', now() - make_interval(secs => raw_secs))
+        ) as expired
+        group by device_pubkey, property_name, date_trunc('hour', property_time)
+        on conflict do nothing
+        "#
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"
+        delete from property_history
+        using effective_retention
+        where effective_retention.device_pubkey = property_history.device_pubkey
+            and effective_retention.property_name = property_history.property_name
+            and property_time < date_trunc('hour', now() - make_interval(secs => raw_secs))
+        "#
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"
+        delete from property_rollup
+        using effective_retention
+        where effective_retention.device_pubkey = property_rollup.device_pubkey
+            and effective_retention.property_name = property_rollup.property_name
+            and rollup_time < now() - make_interval(secs => rollup_secs)
+        "#
+    )
+    .execute(&mut tx)
     .await?;
+    tx.commit().await?;
     Ok(())
 }
+pub async fn retention_task() {
+    loop {
+        if let Err(e) = db_enforce_retention().await {
+            tide::log::error!("failed to enforce retention: {}", e);
+        }
+        if let Err(e) = db_prune_commands().await {
+            tide::log::error!("failed to prune commands: {}", e);
+        }
+        async_std::task::sleep(Duration::from_secs(3600)).await;
+    }
+}
+
+#[derive(Serialize)]
+pub struct PropertyRollup {
+    pub rollup_time: DateTime<Utc>,
+    pub rollup_min: f64,
+    pub rollup_max: f64,
+    pub rollup_avg: f64,
+    pub rollup_count: i64,
+    pub rollup_last: f64,
+}
+pub async fn db_get_property_rollups(
+    username: &str,
+    device: &[u8],
+    property_id: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: i64,
+    ascending: bool,
+) -> Result<Vec<PropertyRollup>> {
+    Ok(query_as!(
+        PropertyRollup,
+        r#"
+            select rollup_time, rollup_min, rollup_max, rollup_avg, rollup_count, rollup_last
+            from property_rollup
+            join account_devices($1) as account_device
+            on account_device.device_pubkey = property_rollup.device_pubkey
+            where property_rollup.device_pubkey = $2
+                and property_name = $3 and property_readable($1, $2, $3)
+                and ($4::timestamptz is null or rollup_time >= $4)
+                and ($5::timestamptz is null or rollup_time < $5)
+            order by
+                case when $7 then rollup_time end asc,
+                case when not $7 then rollup_time end desc
+            limit $6
+            "#,
+        username,
+        device,
+        property_id,
+        from,
+        to,
+        limit,
+        ascending
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+
+#[derive(Serialize)]
+pub struct SchemaVersion {
+    pub schema_version: i32,
+    pub schema_value: Value,
+    pub schema_created: DateTime<Utc>,
+}
+
+pub async fn db_get_schema_versions(username: &str, device: &[u8]) -> Result<Vec<SchemaVersion>> {
+    Ok(query_as!(
+        SchemaVersion,
+        r#"
+            select schema_version, schema_value, schema_created
+            from schema_version join account_devices($1) as account_device
+            on account_device.device_pubkey = schema_version.device_pubkey
+            where schema_version.device_pubkey = $2
+            order by schema_version
+            "#,
+        username,
+        device
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+pub async fn db_get_schema_version(
+    username: &str,
+    device: &[u8],
+    version: i32,
+) -> Result<Option<SchemaVersion>> {
+    Ok(query_as!(
+        SchemaVersion,
+        r#"
+            select schema_version, schema_value, schema_created
+            from schema_version join account_devices($1) as account_device
+            on account_device.device_pubkey = schema_version.device_pubkey
+            where schema_version.device_pubkey = $2
+                and schema_version = $3
+            "#,
+        username,
+        device,
+        version
+    )
+    .fetch_optional(&*DB)
+    .await?)
+}
+pub async fn db_device_archive_dropped(username: &str, pubkey: &[u8], enabled: bool) -> Result<()> {
+    query!(
+        r#"
+        update device 
+        set device_archive_dropped = $3 
+        where device_pubkey = $2 and 0 < (
+            select count(*) from device
+            join account_devices($1) as account_device
+            on account_device.device_pubkey = device.device_pubkey
+            where device.device_pubkey = $2 and link_owner
+            )"#,
+        username,
+        pubkey,
+        enabled
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct ArchivedProperty {
+    pub property_name: String,
+    pub property_value: Value,
+    pub schema_version: i32,
+    pub archive_time: DateTime<Utc>,
+}
+
+pub async fn db_get_archived_properties(
+    username: &str,
+    device: &[u8],
+) -> Result<Vec<ArchivedProperty>> {
+    Ok(query_as!(
+        ArchivedProperty,
+        r#"
+            select property_name, property_value, schema_version, archive_time
+            from property_archive join account_devices($1) as account_device
+            on account_device.device_pubkey = property_archive.device_pubkey
+            where property_archive.device_pubkey = $2
+                and (link_owner or link_all_property)
+            order by archive_time
+            "#,
+        username,
+        device
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+
+#[derive(Serialize)]
+pub struct ClaimCode {
+    pub claim_code: String,
+    pub claim_expire: DateTime<Utc>,
+}
+
+/// Issues a short single-use code a device presents to link itself to `username`.
+pub async fn db_create_claim_code(username: &str) -> Result<ClaimCode> {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let code: String = (0..8)
+        .map(|_| ALPHABET[OsRng.next_u32() as usize % ALPHABET.len()] as char)
+        .collect();
+
+    query!(
+        r#"delete from claim_code
+            where claim_expire < now()"#
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(query_as!(
+        ClaimCode,
+        r#"
+            insert into claim_code (claim_code, account_username, claim_expire)
+            values ($1, $2, now() + interval '10 minutes')
+            returning claim_code, claim_expire
+            "#,
+        code,
+        username
+    )
+    .fetch_one(&*DB)
+    .await?)
+}
+
+#[derive(Serialize)]
+pub struct PropertyGrant {
+    pub account_username: String,
+    pub property_name: String,
+    pub grant_read: bool,
+    pub grant_write: bool,
+}
+
+pub async fn db_get_property_grants(device: &[u8]) -> Result<Vec<PropertyGrant>> {
+    Ok(query_as!(
+        PropertyGrant,
+        r#"
+            select account_username, property_name, grant_read, grant_write
+            from property_grant
+            where device_pubkey = $1
+            order by account_username, property_name
+            "#,
+        device
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+pub async fn db_get_account_property_grants(
+    username: &str,
+    device: &[u8],
+) -> Result<Vec<PropertyGrant>> {
+    Ok(query_as!(
+        PropertyGrant,
+        r#"
+            select account_username, property_name, grant_read, grant_write
+            from property_grant
+            where account_username = $1 and device_pubkey = $2
+            "#,
+        username,
+        device
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+/// Grants `username` access to one property, linking it to the device if needed.
+pub async fn db_set_property_grant(
+    username: &str,
+    device: &[u8],
+    property_id: &str,
+    read: bool,
+    write: bool,
+) -> Result<()> {
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"
+            insert into link_account_device (account_username, device_pubkey)
+            values ($1, $2)
+            on conflict (account_username, device_pubkey) do nothing
+            "#,
+        username,
+        device
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"
+            insert into property_grant
+                (account_username, device_pubkey, property_name, grant_read, grant_write)
+            values ($1, $2, $3, $4, $5)
+            on conflict (account_username, device_pubkey, property_name)
+            do update
+            set grant_read = $4, grant_write = $5
+            "#,
+        username,
+        device,
+        property_id,
+        read,
+        write
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+pub async fn db_delete_property_grant(
+    username: &str,
+    device: &[u8],
+    property_id: &str,
+) -> Result<bool> {
+    Ok(query!(
+        r#"
+            delete from property_grant
+            where account_username = $1 and device_pubkey = $2 and property_name = $3
+            "#,
+        username,
+        device,
+        property_id
+    )
+    .execute(&*DB)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Links `username` to a device as owner or viewer, refusing to demote its
+/// last owner.
+pub async fn db_share_device(username: &str, device: &[u8], owner: bool) -> Result<bool> {
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"
+            insert into link_account_device
+                (account_username, device_pubkey, link_owner, link_all_property)
+            values ($1, $2, $3, true)
+            on conflict (account_username, device_pubkey)
+            do update
+            set link_owner = $3, link_all_property = true
+            "#,
+        username,
+        device,
+        owner
+    )
+    .execute(&mut tx)
+    .await?;
+    let owners = query!(
+        r#"
+            select count(*) as "count!" from link_account_device
+            where device_pubkey = $1 and link_owner
+            "#,
+        device
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    if owners.count == 0 {
+        return Ok(false);
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+/// Removes `username`'s access to a device, refusing to drop its last owner.
+pub async fn db_revoke_device(username: &str, device: &[u8]) -> Result<bool> {
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"
+            delete from link_account_device
+            where account_username = $1 and device_pubkey = $2
+            "#,
+        username,
+        device
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"
+            delete from property_grant
+            where account_username = $1 and device_pubkey = $2
+            "#,
+        username,
+        device
+    )
+    .execute(&mut tx)
+    .await?;
+    let owners = query!(
+        r#"
+            select count(*) as "count!" from link_account_device
+            where device_pubkey = $1 and link_owner
+            "#,
+        device
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    if owners.count == 0 {
+        return Ok(false);
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+/// Hands `from`'s ownership of a device to `to`; `from` stays a viewer if `keep_access`.
+pub async fn db_transfer_device(
+    from: &str,
+    to: &str,
+    device: &[u8],
+    keep_access: bool,
+) -> Result<()> {
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"
+            insert into link_account_device
+                (account_username, device_pubkey, link_owner, link_all_property)
+            values ($1, $2, true, true)
+            on conflict (account_username, device_pubkey)
+            do update
+            set link_owner = true, link_all_property = true
+            "#,
+        to,
+        device
+    )
+    .execute(&mut tx)
+    .await?;
+    if keep_access {
+        query!(
+            r#"
+            update link_account_device
+            set link_owner = false, link_all_property = true
+            where account_username = $1 and device_pubkey = $2
+            "#,
+            from,
+            device
+        )
+        .execute(&mut tx)
+        .await?;
+    } else {
+        query!(
+            r#"
+            delete from link_account_device
+            where account_username = $1 and device_pubkey = $2
+            "#,
+            from,
+            device
+        )
+        .execute(&mut tx)
+        .await?;
+        query!(
+            r#"
+            delete from property_grant
+            where account_username = $1 and device_pubkey = $2
+            "#,
+            from,
+            device
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+/// Whether `username` owns a device through its own link rather than one
+/// inherited from a descendant.
+pub async fn db_is_direct_owner(username: &str, device: &[u8]) -> Result<bool> {
+    Ok(query!(
+        r#"select exists (
+                select 1 from link_account_device
+                where account_username = $1 and device_pubkey = $2 and link_owner
+            ) as "owner!""#,
+        username,
+        device
+    )
+    .fetch_one(&*DB)
+    .await?
+    .owner)
+}
+
+/// Deletes a device owned by `username` together with everything recorded for it.
+pub async fn db_delete_device(username: &str, pubkey: &[u8]) -> Result<bool> {
+    Ok(query!(
+        r#"
+        delete from device
+        where device_pubkey = $2 and exists (
+            select 1 from account_devices($1)
+            where device_pubkey = $2 and link_owner
+            )"#,
+        username,
+        pubkey
+    )
+    .execute(&*DB)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+#[derive(Serialize)]
+pub struct PropertyExport {
+    pub property_name: String,
+    pub property_value: Value,
+    pub property_time: DateTime<Utc>,
+}
+
+/// History records of a device that `username` may read, oldest first, in
+/// pages of at most `limit` starting at `from`.
+pub async fn db_export_device_history(
+    username: &str,
+    device: &[u8],
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: i64,
+) -> Result<Vec<PropertyExport>> {
+    Ok(query_as!(
+        PropertyExport,
+        r#"
+            select property_name, property_value, property_time
+            from property_history
+            where device_pubkey = $2
+                and (
+                    exists (
+                        select 1 from account_devices($1)
+                        where device_pubkey = $2
+                            and (link_owner or link_all_property)
+                    )
+                    or property_name in (
+                        select property_name from property_grant
+                        where account_username = $1 and device_pubkey = $2 and grant_read
+                    )
+                )
+                and ($3::timestamptz is null or property_time >= $3)
+                and ($4::timestamptz is null or property_time < $4)
+            order by property_time
+            limit $5
+            "#,
+        username,
+        device,
+        from,
+        to,
+        limit
+    )
+    .fetch_all(&*DB)
+    .await?)
+}
+
+/// Whether `ancestor` is a direct or indirect owner of `username`.
+pub async fn db_is_ancestor(ancestor: &str, username: &str) -> Result<bool> {
+    Ok(query!(
+        r#"
+            with recursive ancestor(account_username) as (
+                select account_username from link_account_account
+                where derive_account_username = $2
+                union
+                select link_account_account.account_username from link_account_account
+                join ancestor
+                on link_account_account.derive_account_username = ancestor.account_username
+            )
+            select exists (
+                select 1 from ancestor where account_username = $1
+            ) as "is_ancestor!"
+            "#,
+        ancestor,
+        username
+    )
+    .fetch_one(&*DB)
+    .await?
+    .is_ancestor)
+}
+pub async fn db_set_account_disabled(username: &str, disabled: bool) -> Result<()> {
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"update account
+            set account_disabled = $2
+            where account_username = $1"#,
+        username,
+        disabled
+    )
+    .execute(&mut tx)
+    .await?;
+    if disabled {
+        query!(
+            r#"delete from session
+                where account_username = $1"#,
+            username
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+pub async fn db_delete_account(username: &str) -> Result<()> {
+    query!(
+        r#"delete from account
+            where account_username = $1"#,
+        username
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+/// Lets `owner` see the devices of its direct child `username`.
+pub async fn db_set_inherit_device(owner: &str, username: &str, enabled: bool) -> Result<bool> {
+    Ok(query!(
+        r#"update link_account_account
+            set link_inherit_device = $3
+            where account_username = $1 and derive_account_username = $2"#,
+        owner,
+        username,
+        enabled
+    )
+    .execute(&*DB)
+    .await?
+    .rows_affected()
+        > 0)
+}
+pub async fn db_link_account(owner: &str, username: &str) -> Result<()> {
+    query!(
+        r#"
+            insert into link_account_account (account_username, derive_account_username)
+            values ($1, $2)
+            on conflict do nothing;
+            "#,
+        owner,
+        username
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_set_account_role(username: &str, role: Role) -> Result<()> {
+    query!(
+        r#"update account
+            set account_role = $2
+            where account_username = $1"#,
+        username,
+        role.as_str()
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+/// Failed password attempts are tracked under `account:<username>` and
+/// `ip:<address>` keys, password reset requests under the same keys prefixed
+/// with `reset:`; failures after an hour of quiet start over.
+pub async fn db_throttle_until(keys: &[String]) -> Result<Option<DateTime<Utc>>> {
+    Ok(query!(
+        r#"select max(throttle_until) as "throttle_until"
+            from login_throttle
+            where throttle_key = any($1) and throttle_until > now()"#,
+        keys
+    )
+    .fetch_one(&*DB)
+    .await?
+    .throttle_until)
+}
+
+/// Counts a failure against `key`, blocking it for `delay(failures)`.
+pub async fn db_record_login_failure(key: &str, delay: impl Fn(i32) -> Duration) -> Result<()> {
+    let failures = query!(
+        r#"insert into login_throttle (throttle_key, throttle_failures)
+            values ($1, 1)
+            on conflict (throttle_key) do update
+            set throttle_failures = case
+                    when login_throttle.throttle_last_failure < now() - interval '1 hour' then 1
+                    else login_throttle.throttle_failures + 1
+                end,
+                throttle_last_failure = now()
+            returning throttle_failures"#,
+        key
+    )
+    .fetch_one(&*DB)
+    .await?
+    .throttle_failures;
+    query!(
+        r#"update login_throttle
+            set throttle_until = now() + $2 * interval '1 millisecond'
+            where throttle_key = $1"#,
+        key,
+        delay(failures).as_millis() as f64
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+pub async fn db_clear_login_failures(key: &str) -> Result<()> {
+    query!(
+        r#"delete from login_throttle
+            where throttle_key = $1"#,
+        key
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+pub struct Totp {
+    pub account_totp_secret: Option<Vec<u8>>,
+    pub account_totp_enabled: bool,
+}
+
+pub async fn db_get_totp(username: &str) -> Result<Option<Totp>> {
+    Ok(query_as!(
+        Totp,
+        r#"select account_totp_secret, account_totp_enabled
+            from account
+            where account_username = $1"#,
+        username
+    )
+    .fetch_optional(&*DB)
+    .await?)
+}
+
+/// Stores a new, not yet confirmed secret, or with `None` turns two-factor
+/// authentication off and drops the recovery codes.
+pub async fn db_set_totp_secret(username: &str, secret: Option<&[u8]>) -> Result<()> {
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"update account
+            set account_totp_secret = $2, account_totp_enabled = false,
+                account_totp_last_step = null
+            where account_username = $1"#,
+        username,
+        secret
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"delete from recovery_code
+            where account_username = $1"#,
+        username
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn db_enable_totp(username: &str) -> Result<()> {
+    query!(
+        r#"update account
+            set account_totp_enabled = true
+            where account_username = $1 and account_totp_secret is not null"#,
+        username
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+/// Marks `step` used; false if it or a later step already was.
+pub async fn db_use_totp_step(username: &str, step: i64) -> Result<bool> {
+    Ok(query!(
+        r#"update account
+            set account_totp_last_step = $2
+            where account_username = $1
+                and (account_totp_last_step is null or account_totp_last_step < $2)"#,
+        username,
+        step
+    )
+    .execute(&*DB)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Replaces the account's recovery codes, which are stored as SHA-256.
+pub async fn db_set_recovery_codes(username: &str, codes: &[String]) -> Result<()> {
+    let hashes: Vec<Vec<u8>> = codes
+        .iter()
+        .map(|code| Sha256::digest(code.as_bytes()).to_vec())
+        .collect();
+    let mut tx = DB.begin().await?;
+    query!(
+        r#"delete from recovery_code
+            where account_username = $1"#,
+        username
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"insert into recovery_code (account_username, recovery_code_hash)
+            select $1, unnest($2::bytea[])"#,
+        username,
+        &hashes
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Consumes a recovery code; false if the account has no such code.
+pub async fn db_use_recovery_code(username: &str, code: &str) -> Result<bool> {
+    let hash = Sha256::digest(code.trim().as_bytes());
+    Ok(query!(
+        r#"delete from recovery_code
+            where account_username = $1 and recovery_code_hash = $2"#,
+        username,
+        hash.as_slice()
+    )
+    .execute(&*DB)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+pub async fn db_get_account_email(username: &str) -> Result<Option<String>> {
+    Ok(query!(
+        r#"select account_email
+            from account
+            where account_username = $1"#,
+        username
+    )
+    .fetch_optional(&*DB)
+    .await?
+    .and_then(|account| account.account_email))
+}
+
+pub async fn db_set_account_email(username: &str, email: Option<&str>) -> Result<()> {
+    query!(
+        r#"update account
+            set account_email = $2
+            where account_username = $1"#,
+        username,
+        email
+    )
+    .execute(&*DB)
+    .await?;
+    Ok(())
+}
+
+pub struct PasswordReset {
+    pub token: Vec<u8>,
+    pub expire: DateTime<Utc>,
+}
+
+/// Issues a reset token valid for an hour, replacing any earlier one. Only
+/// its SHA-256 is stored.
+pub async fn db_create_password_reset(username: &str) -> Result<PasswordReset> {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    let hash = Sha256::digest(token);
+
+    query!(
+        r#"delete from password_reset
+            where password_reset_expire < now() or account_username = $1"#,
+        username
+    )
+    .execute(&*DB)
+    .await?;
+    let expire = query!(
+        r#"
+            insert into password_reset (password_reset_hash, account_username,
+                password_reset_expire)
+            values ($1, $2, now() + interval '1 hour')
+            returning password_reset_expire
+            "#,
+        hash.as_slice(),
+        username
+    )
+    .fetch_one(&*DB)
+    .await?
+    .password_reset_expire;
+    Ok(PasswordReset {
+        token: token.to_vec(),
+        expire,
+    })
+}
+
+/// The account an unexpired reset token belongs to.
+pub async fn db_get_password_reset_account(token: &[u8]) -> Result<Option<String>> {
+    let hash = Sha256::digest(token);
+    Ok(query!(
+        r#"select account_username
+            from password_reset
+            where password_reset_hash = $1 and password_reset_expire > now()"#,
+        hash.as_slice()
+    )
+    .fetch_optional(&*DB)
+    .await?
+    .map(|reset| reset.account_username))
+}
+
+/// Consumes a reset token and sets the password, ending all sessions and
+/// lifting any lockout. False if the token is unknown, used or expired.
+pub async fn db_reset_password(token: &[u8], password: &str) -> Result<bool> {
+    let hash = Sha256::digest(token);
+    let mut tx = DB.begin().await?;
+    let Some(reset) = query!(
+        r#"delete from password_reset
+            where password_reset_hash = $1 and password_reset_expire > now()
+            returning account_username"#,
+        hash.as_slice()
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    else {
+        return Ok(false);
+    };
+    query!(
+        r#"update account
+            set account_password = $2, account_must_change_password = false
+            where account_username = $1"#,
+        reset.account_username,
+        password::hash(password)?
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"delete from session
+            where account_username = $1"#,
+        reset.account_username
+    )
+    .execute(&mut tx)
+    .await?;
+    query!(
+        r#"delete from login_throttle
+            where throttle_key = $1"#,
+        format!("account:{}", reset.account_username)
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
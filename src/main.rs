@@ -1,35 +1,198 @@
 mod api;
 mod database;
+mod notify;
+mod password;
 mod remote;
+mod schema;
+mod totp;
+
+use database::Role;
 
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv()?;
+    password::init()?;
+    notify::init()?;
     database::migrate().await?;
+    let insecure_dev = std::env::args().any(|arg| arg == "--insecure-dev");
+    database::bootstrap_admin(insecure_dev).await?;
+    async_std::task::spawn(database::retention_task());
     tide::log::start();
 
     let mut server = tide::new();
 
     server.at("/device/new").post(remote::new_device);
-    server.at("/device/:device/schema").post(remote::put_schema);
+    server
+        .at("/device/:device/schema")
+        .with(remote::VerifyDevice)
+        .post(remote::put_schema);
     server
         .at("/device/:device/local_ip")
+        .with(remote::VerifyDevice)
         .post(remote::put_local_ip);
-    server.at("/device/:device/data/set").post(remote::put_data);
+    server
+        .at("/device/:device/data/set")
+        .with(remote::VerifyDevice)
+        .post(remote::put_data);
     server
         .at("/device/:device/data/wait")
+        .with(remote::VerifyDevice)
         .post(remote::wait_data);
+    server
+        .at("/device/:device/command/ack")
+        .with(remote::VerifyDevice)
+        .post(remote::ack_command);
 
     server.at("/api/account/new").post(api::create_account);
-    server.at("/api/account/name").post(api::get_account_name);
-    server.at("/api/account/new_password").post(api::chpasswd);
-    server.at("/api/list_device").post(api::list_device);
-    server.at("/api/list_account").post(api::list_account);
-    server.at("/api/device/local_ip").post(api::get_local_ip);
-    server.at("/api/device/title/new").post(api::set_title);
-    server.at("/api/device/schema").post(api::get_schema);
-    server.at("/api/property/get").post(api::get_properties);
-    server.at("/api/property/set").post(api::set_properties);
+    server.at("/api/session/new").post(api::login);
+    server
+        .at("/api/password_reset/new")
+        .post(api::request_password_reset);
+    server
+        .at("/api/password_reset/confirm")
+        .post(api::confirm_password_reset);
+
+    let mut api = tide::new();
+    api.with(api::Authenticate);
+    api.at("/session/delete")
+        .with(api::Require(Role::Viewer))
+        .post(api::logout);
+    api.at("/session/delete_all")
+        .with(api::Require(Role::Viewer))
+        .post(api::logout_all);
+    api.at("/account/name")
+        .with(api::Require(Role::Viewer))
+        .post(api::get_account_name);
+    api.at("/account/new_password")
+        .with(api::Require(Role::Viewer))
+        .post(api::chpasswd);
+    api.at("/account/totp/enroll")
+        .with(api::Require(Role::Viewer))
+        .post(api::enroll_totp);
+    api.at("/account/totp/confirm")
+        .with(api::Require(Role::Viewer))
+        .post(api::confirm_totp);
+    api.at("/account/totp/disable")
+        .with(api::Require(Role::Viewer))
+        .post(api::disable_totp);
+    api.at("/account/totp/recovery_codes")
+        .with(api::Require(Role::Viewer))
+        .post(api::new_recovery_codes);
+    api.at("/account/email")
+        .with(api::Require(Role::Viewer))
+        .post(api::set_email);
+    api.at("/account/disable")
+        .with(api::Require(Role::User))
+        .post(api::disable_account);
+    api.at("/account/enable")
+        .with(api::Require(Role::User))
+        .post(api::enable_account);
+    api.at("/account/unlock")
+        .with(api::Require(Role::User))
+        .post(api::unlock_account);
+    api.at("/account/delete")
+        .with(api::Require(Role::User))
+        .post(api::delete_account);
+    api.at("/account/link")
+        .with(api::Require(Role::User))
+        .post(api::link_account);
+    api.at("/account/role")
+        .with(api::Require(Role::Installer))
+        .post(api::set_role);
+    api.at("/account/inherit_device")
+        .with(api::Require(Role::User))
+        .post(api::set_inherit_device);
+    api.at("/list_device")
+        .with(api::Require(Role::Viewer))
+        .post(api::list_device);
+    api.at("/list_device/pending")
+        .with(api::Require(Role::Viewer))
+        .post(api::list_pending_device);
+    api.at("/list_account")
+        .with(api::Require(Role::Viewer))
+        .post(api::list_account);
+    api.at("/device/local_ip")
+        .with(api::Require(Role::Viewer))
+        .post(api::get_local_ip);
+    api.at("/device/title/new")
+        .with(api::Require(Role::User))
+        .post(api::set_title);
+    api.at("/device/claim_code")
+        .with(api::Require(Role::User))
+        .post(api::new_claim_code);
+    api.at("/device/accept")
+        .with(api::Require(Role::User))
+        .post(api::accept_device);
+    api.at("/device/reject")
+        .with(api::Require(Role::User))
+        .post(api::reject_device);
+    api.at("/device/decommission")
+        .with(api::Require(Role::User))
+        .post(api::decommission_device);
+    api.at("/device/export")
+        .with(api::Require(Role::Viewer))
+        .post(api::export_device);
+    api.at("/device/delete")
+        .with(api::Require(Role::User))
+        .post(api::delete_device);
+    api.at("/device/share")
+        .with(api::Require(Role::User))
+        .post(api::share_device);
+    api.at("/device/revoke")
+        .with(api::Require(Role::User))
+        .post(api::revoke_device);
+    api.at("/device/transfer")
+        .with(api::Require(Role::User))
+        .post(api::transfer_device);
+    api.at("/device/grant/list")
+        .with(api::Require(Role::Viewer))
+        .post(api::list_property_grant);
+    api.at("/device/grant/set")
+        .with(api::Require(Role::User))
+        .post(api::set_property_grant);
+    api.at("/device/grant/delete")
+        .with(api::Require(Role::User))
+        .post(api::delete_property_grant);
+    api.at("/device/schema")
+        .with(api::Require(Role::Viewer))
+        .post(api::get_schema);
+    api.at("/device/schema/versions")
+        .with(api::Require(Role::Viewer))
+        .post(api::list_schema_versions);
+    api.at("/device/schema/diff")
+        .with(api::Require(Role::Viewer))
+        .post(api::diff_schema_versions);
+    api.at("/device/schema/archive_dropped")
+        .with(api::Require(Role::User))
+        .post(api::set_archive_dropped);
+    api.at("/property/get")
+        .with(api::Require(Role::Viewer))
+        .post(api::get_properties);
+    api.at("/property/set")
+        .with(api::Require(Role::User))
+        .post(api::set_properties);
+    api.at("/property/history")
+        .with(api::Require(Role::Viewer))
+        .post(api::get_property_history);
+    api.at("/property/rollup")
+        .with(api::Require(Role::Viewer))
+        .post(api::get_property_rollup);
+    api.at("/property/archived")
+        .with(api::Require(Role::Viewer))
+        .post(api::list_archived_properties);
+    api.at("/command/status")
+        .with(api::Require(Role::Viewer))
+        .post(api::get_command);
+    api.at("/retention/list")
+        .with(api::Require(Role::Viewer))
+        .post(api::list_retention);
+    api.at("/retention/set")
+        .with(api::Require(Role::User))
+        .post(api::set_retention);
+    api.at("/retention/delete")
+        .with(api::Require(Role::User))
+        .post(api::delete_retention);
+    server.at("/api").nest(api);
 
     server.listen("0.0.0.0:8080").await?;
     Ok(())
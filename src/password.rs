@@ -0,0 +1,197 @@
+//! Password hashing and strength rules.
+//!
+//! Both are configured from the environment when the server starts:
+//!
+//! - `PASSWORD_MIN_LENGTH` / `PASSWORD_MAX_LENGTH`: allowed length in
+//!   characters, 8 and 128 by default.
+//! - `PASSWORD_BREACHED_FILE`: a local file of known-breached passwords, one
+//!   per line, which are rejected outright.
+//! - `ARGON2_M_COST` / `ARGON2_T_COST` / `ARGON2_P_COST`: Argon2id parameters
+//!   for new hashes. Hashes made with other parameters still verify and are
+//!   upgraded on the account's next login.
+
+use std::{collections::HashSet, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+};
+use once_cell::sync::{Lazy, OnceCell};
+
+struct Policy {
+    min_length: usize,
+    max_length: usize,
+    breached: HashSet<String>,
+    params: Params,
+}
+
+static POLICY: OnceCell<Policy> = OnceCell::new();
+
+fn env<T: FromStr>(name: &str, default: T) -> Result<T> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| anyhow!("Invalid {}: `{}`", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Loads the policy from the environment; must run before any other call.
+pub fn init() -> Result<()> {
+    let breached = match std::env::var("PASSWORD_BREACHED_FILE") {
+        Ok(path) => std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read PASSWORD_BREACHED_FILE `{}`", path))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => HashSet::new(),
+    };
+    let params = Params::new(
+        env("ARGON2_M_COST", Params::DEFAULT_M_COST)?,
+        env("ARGON2_T_COST", Params::DEFAULT_T_COST)?,
+        env("ARGON2_P_COST", Params::DEFAULT_P_COST)?,
+        None,
+    )
+    .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+    let policy = Policy {
+        min_length: env("PASSWORD_MIN_LENGTH", 8)?,
+        max_length: env("PASSWORD_MAX_LENGTH", 128)?,
+        breached,
+        params,
+    };
+    POLICY
+        .set(policy)
+        .map_err(|_| anyhow!("Password policy already loaded"))
+}
+
+fn policy() -> &'static Policy {
+    POLICY.get().expect("password::init must run first")
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, policy().params.clone())
+}
+
+/// Checks `password` against the strength rules.
+pub fn check(username: &str, password: &str) -> Result<(), String> {
+    let policy = policy();
+    let length = password.chars().count();
+    if length < policy.min_length {
+        Err(format!(
+            "Password must be at least {} characters",
+            policy.min_length
+        ))
+    } else if length > policy.max_length {
+        Err(format!(
+            "Password must be at most {} characters",
+            policy.max_length
+        ))
+    } else if password == username {
+        Err("Password must differ from the username".to_string())
+    } else if policy.breached.contains(password) {
+        Err("Password appears in a list of breached passwords".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Hashes `password` with the configured Argon2 parameters.
+pub fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("{}", e))?
+        .to_string())
+}
+
+/// Checks `password` against a PHC string; malformed hashes never match.
+pub fn verify(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed_hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok()
+    })
+}
+
+/// Takes as long as [`verify`] against a real hash, so that a missing
+/// account cannot be told apart from a wrong password by timing.
+pub fn verify_dummy(password: &str) {
+    static DUMMY: Lazy<String> = Lazy::new(|| hash("").expect("hashing must work"));
+    verify(&DUMMY, password);
+}
+
+/// Whether `hash` was made with other than the configured parameters.
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    let params = &policy().params;
+    parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || Params::try_from(&parsed_hash).map_or(true, |current| {
+            current.m_cost() != params.m_cost()
+                || current.t_cost() != params.t_cost()
+                || current.p_cost() != params.p_cost()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() {
+        POLICY.get_or_init(|| Policy {
+            min_length: 8,
+            max_length: 16,
+            breached: ["password123".to_string()].into_iter().collect(),
+            params: Params::new(Params::DEFAULT_M_COST, 2, 1, None).unwrap(),
+        });
+    }
+
+    #[test]
+    fn check_enforces_rules() {
+        setup();
+        assert!(check("alice", "correct-horse").is_ok());
+        assert!(check("alice", "short").is_err());
+        assert!(check("alice", "a-password-that-is-too-long").is_err());
+        assert!(check("alice-smith", "alice-smith").is_err());
+        assert!(check("alice", "password123").is_err());
+    }
+
+    #[test]
+    fn check_counts_characters_not_bytes() {
+        setup();
+        assert!(check("alice", "ééééééé").is_err());
+        assert!(check("alice", "éééééééé").is_ok());
+    }
+
+    #[test]
+    fn hash_round_trips() {
+        setup();
+        let hash = hash("correct-horse").unwrap();
+        assert!(verify(&hash, "correct-horse"));
+        assert!(!verify(&hash, "wrong-horse"));
+        assert!(!verify("not a hash", "correct-horse"));
+    }
+
+    #[test]
+    fn needs_rehash_on_parameter_change() {
+        setup();
+        assert!(!needs_rehash(&hash("correct-horse").unwrap()));
+        let salt = SaltString::generate(&mut OsRng);
+        let old = Argon2::default()
+            .hash_password(b"correct-horse", &salt)
+            .unwrap()
+            .to_string();
+        assert!(needs_rehash(&old));
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, policy().params.clone())
+            .hash_password(b"correct-horse", &salt)
+            .unwrap()
+            .to_string();
+        assert!(needs_rehash(&argon2i));
+        assert!(needs_rehash("not a hash"));
+    }
+}
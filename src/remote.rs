@@ -1,72 +1,360 @@
-use std::{collections::BTreeMap, net::Ipv4Addr, time::Duration};
+use std::{
+    collections::BTreeMap,
+    net::Ipv4Addr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use async_std::sync::Mutex;
+use async_std::{
+    channel::{self, Receiver, Sender},
+    future,
+    sync::Mutex,
+};
 use base58::FromBase58;
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::query;
-use tide::{Request, Response};
+use sha2::{Digest, Sha256};
+use sqlx::{query, Postgres, Transaction};
+use tide::{Middleware, Next, Request, Response};
 
-use crate::database::DB;
+use crate::{
+    database::{db_expire_commands, DB},
+    schema,
+};
 
-static WAITER: Mutex<BTreeMap<Vec<u8>, BTreeMap<String, Value>>> = Mutex::new(BTreeMap::new());
-pub async fn set_wait(device: &[u8], properties: BTreeMap<String, Value>) {
-    WAITER
+/// How far a request's timestamp may drift from the server clock, in milliseconds.
+const MAX_CLOCK_SKEW: u64 = 30_000;
+
+/// Signatures accepted within the last `MAX_CLOCK_SKEW` window, with their timestamp.
+static SEEN: Mutex<BTreeMap<Vec<u8>, u64>> = Mutex::new(BTreeMap::new());
+
+/// Verifies that a device request is signed by `pubkey`.
+///
+/// The device sends `X-Sliot-Timestamp` (unix milliseconds) and `X-Sliot-Signature`
+/// (base58 Ed25519 signature) over
+/// `"{method}\n{path}\n{query}\n{timestamp}\n{hex sha256 of body}"`, where
+/// `query` is the raw query string without `?`, empty when there is none.
+/// Stale timestamps and signatures already seen are rejected.
+async fn verify(req: &Request<()>, pubkey: &[u8], body: &[u8]) -> bool {
+    let Ok(pubkey) = <[u8; 32]>::try_from(pubkey) else {
+        return false;
+    };
+    let Ok(pubkey) = VerifyingKey::from_bytes(&pubkey) else {
+        return false;
+    };
+    let Some(timestamp) = req
+        .header("X-Sliot-Timestamp")
+        .and_then(|h| h.as_str().parse::<u64>().ok())
+    else {
+        return false;
+    };
+    let Some(signature) = req
+        .header("X-Sliot-Signature")
+        .and_then(|h| h.as_str().from_base58().ok())
+        .and_then(|s| Signature::from_slice(&s).ok())
+    else {
+        return false;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    if now.abs_diff(timestamp) > MAX_CLOCK_SKEW {
+        return false;
+    }
+
+    let message = format!(
+        "{}\n{}\n{}\n{}\n{:x}",
+        req.method(),
+        req.url().path(),
+        req.url().query().unwrap_or(""),
+        timestamp,
+        Sha256::digest(body)
+    );
+    if pubkey.verify(message.as_bytes(), &signature).is_err() {
+        return false;
+    }
+
+    let mut seen = SEEN.lock().await;
+    seen.retain(|_, t| now.abs_diff(*t) <= MAX_CLOCK_SKEW);
+    seen.insert(signature.to_vec(), timestamp).is_none()
+}
+
+/// Rejects requests to `/device/:device/*` that are not signed by `:device`
+/// or come from a rejected or decommissioned device.
+pub struct VerifyDevice;
+
+struct DeviceStatus(String);
+
+/// Whether the owner accepted the device verified by [`VerifyDevice`].
+fn accepted(req: &Request<()>) -> bool {
+    matches!(req.ext::<DeviceStatus>(), Some(DeviceStatus(status)) if status == "accepted")
+}
+
+#[tide::utils::async_trait]
+impl Middleware<()> for VerifyDevice {
+    async fn handle(&self, mut req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        if let Ok(pubkey) = req.param("device")?.from_base58() {
+            let body = req.body_bytes().await?;
+            if verify(&req, &pubkey, &body).await {
+                let device = query!(
+                    r#"
+                    select device_status from device
+                    where device_pubkey = $1
+                    "#,
+                    pubkey
+                )
+                .fetch_optional(&*DB)
+                .await?;
+                return match device {
+                    None => Ok(Response::builder(404).build()),
+                    Some(device)
+                        if device.device_status == "rejected"
+                            || device.device_status == "decommissioned" =>
+                    {
+                        Ok(Response::builder(403).build())
+                    }
+                    Some(device) => {
+                        req.set_ext(DeviceStatus(device.device_status));
+                        req.set_body(body);
+                        Ok(next.run(req).await)
+                    }
+                };
+            }
+        }
+        Ok(Response::builder(401).build())
+    }
+}
+
+/// Default and upper bound for how long `wait_data` parks, in seconds.
+const DEFAULT_WAIT_TIMEOUT: u64 = 30;
+const MAX_WAIT_TIMEOUT: u64 = 300;
+
+type Notifier = (Sender<()>, Receiver<()>);
+static NOTIFIER: Mutex<BTreeMap<Vec<u8>, Notifier>> = Mutex::new(BTreeMap::new());
+
+async fn notifier(device: &[u8]) -> Notifier {
+    NOTIFIER
         .lock()
         .await
         .entry(device.to_owned())
-        .or_default()
-        .extend(properties);
+        .or_insert_with(|| channel::bounded(1))
+        .clone()
+}
+/// Wakes anyone parked in `wait_data` for a deleted `device` and drops its channel.
+pub async fn forget_device(device: &[u8]) {
+    if let Some((notify, _)) = NOTIFIER.lock().await.remove(device) {
+        notify.close();
+    }
+}
+/// Queues `properties` for `device` and returns the new command's id.
+///
+/// A command still queued at `expire` is never delivered.
+pub async fn set_wait(
+    device: &[u8],
+    properties: BTreeMap<String, Value>,
+    expire: Option<DateTime<Utc>>,
+) -> anyhow::Result<i64> {
+    let command = query!(
+        r#"
+        insert into command (device_pubkey, command_properties, command_expire)
+        values ($1, $2, $3)
+        returning command_id
+        "#,
+        device,
+        serde_json::to_value(properties)?,
+        expire
+    )
+    .fetch_one(&*DB)
+    .await?;
+    let (notify, _) = notifier(device).await;
+    let _ = notify.try_send(());
+    Ok(command.command_id)
 }
-async fn wait(device: &[u8]) -> Option<BTreeMap<String, Value>> {
-    WAITER.lock().await.remove(device)
+#[derive(Serialize)]
+struct Delivery {
+    id: i64,
+    properties: Value,
+}
+/// Seconds a delivered command may go unacknowledged before it is sent again.
+const REDELIVER_AFTER: f64 = 60.0;
+
+/// Marks every queued command for `device` as delivered, oldest first.
+///
+/// Commands are marked before the response is written, so delivery is
+/// at-least-once: one that is not acked within [`REDELIVER_AFTER`], e.g.
+/// because the connection dropped mid-response, is handed out again until it
+/// is acked or expires. Devices should treat a repeated command id as a
+/// duplicate.
+async fn take_commands(device: &[u8]) -> anyhow::Result<Vec<Delivery>> {
+    db_expire_commands(device).await?;
+    let mut commands: Vec<Delivery> = query!(
+        r#"
+        update command
+        set command_status = 'delivered', command_updated = now()
+        where device_pubkey = $1
+            and (command_status = 'queued' or (command_status = 'delivered'
+                and command_updated < now() - make_interval(secs => $2)))
+            and (command_expire is null or command_expire > now())
+        returning command_id, command_properties
+        "#,
+        device,
+        REDELIVER_AFTER
+    )
+    .fetch_all(&*DB)
+    .await?
+    .into_iter()
+    .map(|c| Delivery {
+        id: c.command_id,
+        properties: c.command_properties,
+    })
+    .collect();
+    commands.sort_by_key(|c| c.id);
+    Ok(commands)
+}
+async fn wait(device: &[u8], timeout: Duration) -> anyhow::Result<Option<Vec<Delivery>>> {
+    let (_, notified) = notifier(device).await;
+    let deadline = Instant::now() + timeout;
+    loop {
+        let commands = take_commands(device).await?;
+        if !commands.is_empty() {
+            return Ok(Some(commands));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        if let Ok(Ok(())) = future::timeout(remaining, notified.recv()).await {
+            continue;
+        }
+        return Ok(None);
+    }
+}
+/// Records `schema` as a new version for `device` unless it matches the latest
+/// one, archiving properties it drops if the device has `device_archive_dropped`.
+async fn record_schema(
+    tx: &mut Transaction<'_, Postgres>,
+    device: &[u8],
+    schema: &Value,
+) -> anyhow::Result<()> {
+    let version = query!(
+        r#"
+        insert into schema_version (device_pubkey, schema_version, schema_value)
+        select $1, coalesce(max(schema_version), 0) + 1, $2::json
+        from schema_version
+        where device_pubkey = $1
+        having coalesce(
+            (array_agg(schema_value::jsonb order by schema_version desc))[1] <> $2::json::jsonb,
+            true
+        )
+        returning schema_version
+        "#,
+        device,
+        schema
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let declared = schema.get("properties").and_then(Value::as_object);
+    if let (Some(version), Some(declared)) = (version, declared) {
+        let declared: Vec<String> = declared.keys().cloned().collect();
+        query!(
+            r#"
+            with dropped as (
+                delete from property
+                using device
+                where property.device_pubkey = $1 and device.device_pubkey = $1
+                    and device_archive_dropped and property_name <> all($2)
+                returning property.device_pubkey, property_name, property_value
+            )
+            insert into property_archive (device_pubkey, property_name, property_value, schema_version)
+            select device_pubkey, property_name, property_value, $3 from dropped
+            "#,
+            device,
+            &declared,
+            version.schema_version
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
 }
 pub async fn new_device(mut req: Request<()>) -> tide::Result {
     #[derive(Deserialize)]
     struct Input {
         pubkey: String,
-        username: String,
+        claim_code: Option<String>,
         title: String,
         local_ip: String,
         schema: Value,
     }
+    let body = req.body_bytes().await?;
     if let Ok(Input {
         pubkey,
-        username,
+        claim_code,
         title,
         local_ip,
         schema,
-    }) = req.body_json().await
+    }) = serde_json::from_slice(&body)
     {
         if let Ok(pubkey) = pubkey.from_base58() {
-            query!(
+            if !verify(&req, &pubkey, &body).await {
+                return Ok(Response::builder(401).build());
+            }
+            if let Err(message) = schema::check(&schema) {
+                return Ok(Response::builder(422).body(message).build());
+            }
+            let mut tx = DB.begin().await?;
+            let result = query!(
                 r#"
-                insert into device (device_pubkey, device_accepted, device_title, device_local_ip, device_schema)
+                insert into device (device_pubkey, device_status, device_title, device_local_ip, device_schema)
                 values($1, $2, $3, $4, $5)
                 on conflict (device_pubkey)
                 do update 
                 set device_title = $3, device_local_ip = $4, device_schema = $5
+                where device.device_status not in ('rejected', 'decommissioned')
                 ;
             "#,
                 pubkey,
-                false,
+                "pending",
                 title,
                 local_ip,
                 schema,
             )
-            .execute(&*DB)
+            .execute(&mut tx)
             .await?;
-            query!(
-                r#"
-                    insert into link_account_device(account_username, device_pubkey)
-                    values ($1, $2)
-                    on conflict (account_username, device_pubkey) do nothing
+            if result.rows_affected() == 0 {
+                return Ok(Response::builder(403).build());
+            }
+            record_schema(&mut tx, &pubkey, &schema).await?;
+            if let Some(claim_code) = claim_code {
+                let claim = query!(
+                    r#"
+                    delete from claim_code
+                    where claim_code = $1 and claim_expire > now()
+                    returning account_username
+                    "#,
+                    claim_code.trim().to_uppercase()
+                )
+                .fetch_optional(&mut tx)
+                .await?;
+                let Some(claim) = claim else {
+                    return Ok(Response::builder(403).build());
+                };
+                query!(
+                    r#"
+                    insert into link_account_device(account_username, device_pubkey, link_owner)
+                    values ($1, $2, true)
+                    on conflict (account_username, device_pubkey) do update set link_owner = true
                 "#,
-                username,
-                pubkey
-            )
-            .execute(&*DB)
-            .await?;
+                    claim.account_username,
+                    pubkey
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            tx.commit().await?;
             Ok(Response::builder(200).build())
         } else {
             Ok(Response::builder(400).build())
@@ -82,7 +370,11 @@ pub async fn put_schema(mut req: Request<()>) -> tide::Result {
     }
     if let Ok(pubkey) = req.param("device")?.from_base58() {
         if let Ok(Input { schema }) = req.body_json().await {
-            let _result = query!(
+            if let Err(message) = schema::check(&schema) {
+                return Ok(Response::builder(422).body(message).build());
+            }
+            let mut tx = DB.begin().await?;
+            let result = query!(
                 r#"
                 update device
                 set device_schema = $2 
@@ -91,8 +383,12 @@ pub async fn put_schema(mut req: Request<()>) -> tide::Result {
                 pubkey,
                 schema,
             )
-            .execute(&*DB)
+            .execute(&mut tx)
             .await?;
+            if result.rows_affected() > 0 {
+                record_schema(&mut tx, &pubkey, &schema).await?;
+            }
+            tx.commit().await?;
         }
     }
     Ok(Response::builder(200).build())
@@ -103,8 +399,27 @@ pub async fn put_data(mut req: Request<()>) -> tide::Result {
         properties: BTreeMap<String, Value>,
     }
 
+    if !accepted(&req) {
+        return Ok(Response::builder(403).build());
+    }
     if let Ok(pubkey) = req.param("device")?.from_base58() {
         if let Ok(Input { properties }) = req.body_json().await {
+            let device = query!(
+                r#"
+                select device_schema from device
+                where device_pubkey = $1
+                "#,
+                pubkey
+            )
+            .fetch_optional(&*DB)
+            .await?;
+            let Some(device) = device else {
+                return Ok(Response::builder(404).build());
+            };
+            if let Err(message) = schema::validate(&device.device_schema, &properties, false) {
+                return Ok(Response::builder(422).body(message).build());
+            }
+            let mut tx = DB.begin().await?;
             for (k, v) in properties {
                 query!(
                     r#"
@@ -118,16 +433,40 @@ pub async fn put_data(mut req: Request<()>) -> tide::Result {
                     k,
                     v
                 )
-                .execute(&*DB)
+                .execute(&mut tx)
+                .await?;
+                query!(
+                    r#"
+                    insert into property_history(device_pubkey, property_name, property_value)
+                    values($1, $2, $3)
+                    "#,
+                    pubkey,
+                    k,
+                    v
+                )
+                .execute(&mut tx)
                 .await?;
             }
+            tx.commit().await?;
         }
     }
     Ok(Response::builder(200).build())
 }
 pub async fn wait_data(req: Request<()>) -> tide::Result {
-    if let Ok(pubkey) = req.param("device")?.from_base58() {
-        let values = wait(&pubkey).await;
+    #[derive(Deserialize)]
+    struct Query {
+        timeout: Option<u64>,
+    }
+    if !accepted(&req) {
+        return Ok(Response::builder(403).build());
+    }
+    if let (Ok(pubkey), Ok(Query { timeout })) =
+        (req.param("device")?.from_base58(), req.query::<Query>())
+    {
+        let timeout = timeout
+            .unwrap_or(DEFAULT_WAIT_TIMEOUT)
+            .min(MAX_WAIT_TIMEOUT);
+        let values = wait(&pubkey, Duration::from_secs(timeout)).await?;
         Ok(Response::builder(200)
             .body(serde_json::to_value(values)?)
             .build())
@@ -158,3 +497,46 @@ pub async fn put_local_ip(mut req: Request<()>) -> tide::Result {
     }
     Ok(Response::builder(200).build())
 }
+pub async fn ack_command(mut req: Request<()>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct Input {
+        id: i64,
+        success: bool,
+        message: Option<String>,
+    }
+    if !accepted(&req) {
+        return Ok(Response::builder(403).build());
+    }
+    if let Ok(pubkey) = req.param("device")?.from_base58() {
+        if let Ok(Input {
+            id,
+            success,
+            message,
+        }) = req.body_json().await
+        {
+            let status = if success { "acked" } else { "failed" };
+            let result = query!(
+                r#"
+                update command
+                set command_status = $3, command_message = $4, command_updated = now()
+                where device_pubkey = $1 and command_id = $2 and command_status = 'delivered'
+                "#,
+                pubkey,
+                id,
+                status,
+                message
+            )
+            .execute(&*DB)
+            .await?;
+            if result.rows_affected() > 0 {
+                Ok(Response::builder(200).build())
+            } else {
+                Ok(Response::builder(404).build())
+            }
+        } else {
+            Ok(Response::builder(400).build())
+        }
+    } else {
+        Ok(Response::builder(400).build())
+    }
+}
@@ -0,0 +1,119 @@
+//! Validation of reported and commanded properties against `device_schema`.
+//!
+//! A device schema is a JSON Schema for an object whose `properties` are the
+//! device's properties, e.g.
+//!
+//! ```json
+//! {
+//!     "type": "object",
+//!     "properties": {
+//!         "temperature": { "type": "number", "minimum": -40, "readOnly": true },
+//!         "heater": { "type": "boolean" }
+//!     }
+//! }
+//! ```
+//!
+//! Reports and commands carry only some properties, so `required` is ignored.
+//! Names missing from `properties` are rejected, and `readOnly` properties can
+//! be reported by the device but not set by users. A schema without a
+//! `properties` map accepts anything.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+/// Checks that `schema` is a usable JSON Schema.
+pub fn check(schema: &Value) -> Result<(), String> {
+    jsonschema::validator_for(schema)
+        .map(|_| ())
+        .map_err(|e| format!("Invalid schema: {}", e))
+}
+
+/// Validates `properties` against `schema`; `command` marks a user write.
+pub fn validate(
+    schema: &Value,
+    properties: &BTreeMap<String, Value>,
+    command: bool,
+) -> Result<(), String> {
+    let Some(declared) = schema.get("properties").and_then(Value::as_object) else {
+        return Ok(());
+    };
+    for name in properties.keys() {
+        match declared.get(name) {
+            None => return Err(format!("Unknown property `{}`", name)),
+            Some(property) if command && property.get("readOnly") == Some(&Value::Bool(true)) => {
+                return Err(format!("Property `{}` is read-only", name))
+            }
+            _ => {}
+        }
+    }
+
+    let mut schema = schema.clone();
+    if let Some(schema) = schema.as_object_mut() {
+        schema.remove("required");
+    }
+    let validator =
+        jsonschema::validator_for(&schema).map_err(|e| format!("Invalid schema: {}", e))?;
+    let instance = Value::Object(properties.clone().into_iter().collect());
+    if let Some(error) = validator.iter_errors(&instance).next() {
+        return Err(format!("{}: {}", error.instance_path(), error));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "temperature": { "type": "number", "minimum": -40, "readOnly": true },
+                "heater": { "type": "boolean" }
+            },
+            "required": ["temperature", "heater"]
+        })
+    }
+
+    fn properties(value: Value) -> BTreeMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn accepts_partial_reports() {
+        assert!(validate(
+            &schema(),
+            &properties(json!({ "temperature": 21.5 })),
+            false
+        )
+        .is_ok());
+        assert!(validate(&schema(), &properties(json!({ "heater": true })), true).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_properties() {
+        assert!(validate(&schema(), &properties(json!({ "fan": 1 })), false).is_err());
+        assert!(validate(&schema(), &properties(json!({ "heater": "on" })), true).is_err());
+        assert!(validate(&schema(), &properties(json!({ "temperature": -50 })), false).is_err());
+    }
+
+    #[test]
+    fn read_only_properties_cannot_be_commanded() {
+        let temperature = properties(json!({ "temperature": 20 }));
+        assert!(validate(&schema(), &temperature, false).is_ok());
+        assert!(validate(&schema(), &temperature, true).is_err());
+    }
+
+    #[test]
+    fn schema_without_properties_accepts_anything() {
+        assert!(validate(&json!({}), &properties(json!({ "anything": [1, 2] })), true).is_ok());
+    }
+
+    #[test]
+    fn check_rejects_invalid_schemas() {
+        assert!(check(&schema()).is_ok());
+        assert!(check(&json!({ "type": "not-a-type" })).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! RFC 6238 time-based one-time passwords: HMAC-SHA1, six digits, 30 second
+//! steps, as expected by common authenticator apps.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps of clock drift accepted on either side of the current one.
+const SKEW: i64 = 1;
+const ISSUER: &str = "Sliot";
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; 20];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// RFC 4648 base32 without padding, the encoding authenticator apps expect.
+pub fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([0, 0, 0, buf[0], buf[1], buf[2], buf[3], buf[4]]);
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            out.push(ALPHABET[(bits >> (35 - i * 5) & 31) as usize] as char);
+        }
+    }
+    out
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The `otpauth://` URI authenticator apps enroll from, usually as a QR code.
+pub fn provisioning_uri(username: &str, secret: &[u8]) -> String {
+    let issuer = percent_encode(ISSUER);
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}\
+        &algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        issuer = issuer,
+        username = percent_encode(username),
+        secret = base32(secret),
+    )
+}
+
+fn code(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0xf) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step `code` is valid for, if any. Callers must reject
+/// steps at or before the last one accepted so that codes cannot be replayed.
+pub fn verify(secret: &[u8], code: &str) -> Option<i64> {
+    verify_at(secret, code, Utc::now().timestamp())
+}
+
+fn verify_at(secret: &[u8], code: &str, time: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let now = time / STEP;
+    (now - SKEW..=now + SKEW).find(|&step| self::code(secret, step) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc6238_sha1_vectors() {
+        // RFC 6238 appendix B lists eight digits; six-digit codes are their tail.
+        for (time, expected) in [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ] {
+            assert_eq!(
+                code(SECRET, time / STEP),
+                expected % 1_000_000,
+                "T = {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn verify_accepts_skew_and_returns_step() {
+        let time = 1111111111;
+        let step = time / STEP;
+        assert_eq!(verify_at(SECRET, "050471", time), Some(step));
+        assert_eq!(verify_at(SECRET, " 050471 ", time), Some(step));
+        let previous = format!("{:06}", code(SECRET, step - 1));
+        assert_eq!(verify_at(SECRET, &previous, time), Some(step - 1));
+        let stale = format!("{:06}", code(SECRET, step - 2));
+        assert_eq!(verify_at(SECRET, &stale, time), None);
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        let time = 1111111111;
+        assert_eq!(verify_at(SECRET, "50471", time), None);
+        assert_eq!(verify_at(SECRET, "14050471", time), None);
+        assert_eq!(verify_at(SECRET, "05047a", time), None);
+        assert_eq!(verify_at(SECRET, "", time), None);
+    }
+
+    #[test]
+    fn base32_rfc4648_vectors() {
+        for (input, expected) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32(input.as_bytes()), expected);
+        }
+        assert_eq!(base32(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn provisioning_uri_encodes_label() {
+        let uri = provisioning_uri("a:b&c #?", b"\x00");
+        assert_eq!(
+            uri,
+            "otpauth://totp/Sliot:a%3Ab%26c%20%23%3F?secret=AA&issuer=Sliot\
+            &algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}